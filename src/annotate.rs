@@ -0,0 +1,199 @@
+use byteorder::{LittleEndian, BigEndian, WriteBytesExt};
+use elfkit;
+use error::{Error, Result};
+use elfkit::types;
+use elfkit::section::SectionHeader;
+use splitter::{Splitter, ElfCutSection, ELF_CUT_SECTION};
+use std::fs::{File, copy};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const PAGE_SIZE: u64 = 4096;
+const CUT_SECTION_NAME: &'static str = ".archon.cuts";
+
+/// boundaries of sections and segments, plus the page aligned start of each segment,
+/// since that is the granularity segments get mapped with
+pub fn cuts_for(elf: &elfkit::Elf, len: u64) -> Vec<usize> {
+    let mut cuts = Vec::new();
+    for sec in &elf.sections {
+        if sec.header.shtype == types::SectionType::NULL ||
+            sec.header.shtype == types::SectionType::NOBITS {
+            continue;
+        }
+        cuts.push(sec.header.offset);
+        cuts.push(sec.header.offset + sec.header.size);
+    }
+    for seg in &elf.segments {
+        cuts.push(seg.offset - (seg.offset % PAGE_SIZE));
+        cuts.push(seg.offset);
+        cuts.push(seg.offset + seg.filesz);
+    }
+    let mut cuts: Vec<usize> = cuts.into_iter().filter(|c| *c > 0 && *c < len).map(|c| c as usize).collect();
+    cuts.sort_unstable();
+    cuts.dedup();
+    cuts
+}
+
+/// copy an ELF file and append a cut section with good cut points.
+/// nothing of the original is moved, so the cuts refer to the same offsets in both files.
+/// the section and a new section name table are appended, followed by a new section header table.
+pub fn annotate(input: &Path, output: &Path) -> Result<Vec<usize>> {
+    let cannot_read = |e| Error::Io(format!("cannot read {}", input.display()), e);
+    let mut f = try!(File::open(input).map_err(&cannot_read));
+    if ElfCutSection.cuts(&mut f).is_some() {
+        return Err(Error::Invalid(format!("{} already carries a cut section", input.display())));
+    }
+    try!(f.seek(SeekFrom::Start(0)).map_err(&cannot_read));
+    let elf = try!(elfkit::Elf::from_reader(&mut f)
+                   .map_err(|e| Error::Invalid(format!("{}: not an ELF file: {:?}", input.display(), e))));
+    let len = try!(f.metadata().map_err(&cannot_read)).len();
+    let cuts = cuts_for(&elf, len);
+    // the cut section holds 32 bit offsets
+    if let Some(cut) = cuts.iter().find(|c| **c as u64 > u32::max_value() as u64) {
+        return Err(Error::Invalid(format!("{} has a cut at {}, past what a cut section can hold",
+                                          input.display(), cut)));
+    }
+
+    let cannot_write = |e| Error::Io(format!("cannot write {}", output.display()), e);
+    try!(copy(input, output).map_err(&cannot_write));
+    let mut out = try!(::std::fs::OpenOptions::new().write(true).open(output).map_err(&cannot_write));
+    let eh = &elf.header;
+
+    let mut shstrtab = match elf.sections[eh.shstrndx as usize].content {
+        elfkit::section::SectionContent::Raw(ref raw) => raw.clone(),
+        _ => return Err(Error::Invalid(String::from("section name table is not raw"))),
+    };
+    let name = shstrtab.len() as u32;
+    shstrtab.extend_from_slice(CUT_SECTION_NAME.as_bytes());
+    shstrtab.push(0);
+
+    let mut at = align(len, 4);
+    try!(out.seek(SeekFrom::Start(at)).map_err(&cannot_write));
+    let cuts_offset = at;
+    for cut in &cuts {
+        try!(write_uint(eh, &mut out, *cut as u64, 4).map_err(&cannot_write));
+    }
+    at += cuts.len() as u64 * 4;
+
+    let shstrtab_offset = at;
+    try!(out.write_all(&shstrtab).map_err(&cannot_write));
+    at += shstrtab.len() as u64;
+
+    let mut headers: Vec<SectionHeader> = elf.sections.iter().map(|s| s.header.clone()).collect();
+    headers[eh.shstrndx as usize].offset = shstrtab_offset;
+    headers[eh.shstrndx as usize].size   = shstrtab.len() as u64;
+    headers.push(SectionHeader {
+        name:      name,
+        shtype:    types::SectionType(ELF_CUT_SECTION),
+        offset:    cuts_offset,
+        size:      cuts.len() as u64 * 4,
+        addralign: 4,
+        entsize:   4,
+        .. Default::default()
+    });
+
+    let shoff = align(at, 8);
+    try!(out.seek(SeekFrom::Start(shoff)).map_err(&cannot_write));
+    for h in &headers {
+        try!(h.to_writer(eh, &mut out).map_err(|e| match e {
+            elfkit::Error::Io(e) => cannot_write(e),
+            e => Error::Invalid(format!("cannot write section headers to {}: {:?}", output.display(), e)),
+        }));
+    }
+
+    // patch e_shoff and e_shnum in place, leaving the rest of the header alone
+    let (shoff_at, shnum_at, word) = match eh.ident_class {
+        types::Class::Class32 => (0x20, 0x30, 4),
+        types::Class::Class64 => (0x28, 0x3c, 8),
+    };
+    try!(out.seek(SeekFrom::Start(shoff_at)).map_err(&cannot_write));
+    try!(write_uint(eh, &mut out, shoff, word).map_err(&cannot_write));
+    try!(out.seek(SeekFrom::Start(shnum_at)).map_err(&cannot_write));
+    try!(write_uint(eh, &mut out, headers.len() as u64, 2).map_err(&cannot_write));
+
+    Ok(cuts)
+}
+
+/// a number of so many bytes in the byte order of the ELF file
+fn write_uint<W: Write>(eh: &elfkit::Header, out: &mut W, val: u64, bytes: usize) -> io::Result<()> {
+    match eh.ident_endianness {
+        types::Endianness::LittleEndian => out.write_uint::<LittleEndian>(val, bytes),
+        types::Endianness::BigEndian    => out.write_uint::<BigEndian>(val, bytes),
+    }
+}
+
+fn align(at: u64, to: u64) -> u64 {
+    (at + to - 1) / to * to
+}
+
+/// a little endian ELF64 file with a .text section and its section name table, and no segments
+#[cfg(test)]
+fn tiny_elf() -> Vec<u8> {
+    let shstrtab = b"\0.text\0.shstrtab\0";
+    let mut f = vec![0x7f, b'E', b'L', b'F', 2, 1, 1];
+    f.resize(16, 0);
+    f.write_u16::<LittleEndian>(1).unwrap(); //relocatable
+    f.write_u16::<LittleEndian>(62).unwrap(); //x86-64
+    f.write_u32::<LittleEndian>(1).unwrap();
+    f.write_u64::<LittleEndian>(0).unwrap(); //entry
+    f.write_u64::<LittleEndian>(0).unwrap(); //no program headers
+    f.write_u64::<LittleEndian>(104).unwrap(); //section headers
+    f.write_u32::<LittleEndian>(0).unwrap();
+    for &n in &[64, 56, 0, 64, 3, 2] {
+        f.write_u16::<LittleEndian>(n).unwrap();
+    }
+    f.extend_from_slice(&[0x90; 16]);
+    f.extend_from_slice(shstrtab);
+    f.resize(104, 0);
+    let sections: [(u32, u32, u64, u64, u64, u64); 3] = [
+        (0, 0, 0, 0, 0, 0),
+        (1, 1, 6, 64, 16, 16), //name, type, flags, offset, size, alignment
+        (7, 3, 0, 80, shstrtab.len() as u64, 1),
+    ];
+    for &(name, shtype, flags, offset, size, align) in &sections {
+        f.write_u32::<LittleEndian>(name).unwrap();
+        f.write_u32::<LittleEndian>(shtype).unwrap();
+        f.write_u64::<LittleEndian>(flags).unwrap();
+        f.write_u64::<LittleEndian>(0).unwrap();
+        f.write_u64::<LittleEndian>(offset).unwrap();
+        f.write_u64::<LittleEndian>(size).unwrap();
+        f.write_u32::<LittleEndian>(0).unwrap();
+        f.write_u32::<LittleEndian>(0).unwrap();
+        f.write_u64::<LittleEndian>(align).unwrap();
+        f.write_u64::<LittleEndian>(0).unwrap();
+    }
+    f
+}
+
+#[test]
+fn annotated_cuts_read_back() {
+    use std::io::Read;
+    let dir = ::testing::temp_dir("annotate");
+    let original = tiny_elf();
+    File::create(dir.join("tiny")).unwrap().write_all(&original).unwrap();
+    let cuts = annotate(&dir.join("tiny"), &dir.join("annotated")).unwrap();
+    assert_eq!(cuts, vec![64, 80, 97]);
+    assert_eq!(ElfCutSection.cuts(&mut File::open(dir.join("annotated")).unwrap()), Some(Ok(cuts)));
+
+    // past the header, which points at the new section headers, the original bytes stay where they were
+    let mut annotated = Vec::new();
+    File::open(dir.join("annotated")).unwrap().read_to_end(&mut annotated).unwrap();
+    assert_eq!(&annotated[64..original.len()], &original[64..]);
+    assert!(annotate(&dir.join("annotated"), &dir.join("again")).is_err());
+}
+
+#[test]
+fn cuts_past_4_gib_are_refused() {
+    let dir = ::testing::temp_dir("annotate-large");
+    let mut elf = tiny_elf();
+    // move .text to 5 GiB, into the hole of a sparse file
+    (&mut elf[104 + 64 + 24..]).write_u64::<LittleEndian>(5 << 30).unwrap();
+    let mut f = File::create(dir.join("large")).unwrap();
+    f.write_all(&elf).unwrap();
+    f.set_len((5 << 30) + 4096).unwrap();
+    match annotate(&dir.join("large"), &dir.join("annotated")) {
+        Err(Error::Invalid(_)) => (),
+        r => panic!("expected the cut past 4 GiB to be refused, got {:?}", r),
+    }
+    assert!(!dir.join("annotated").exists());
+}
@@ -1,19 +1,41 @@
+use crypt::Crypt;
+use error::{Error, Result};
+use hash::{self, HashAlgo};
 use hex::{ToHex, FromHex};
 use readchain::{Take,Chain};
-use sha2::{Sha256, Digest};
 use std::collections::HashMap;
 use std::ffi::OsString;
-use std::fs::{File, create_dir_all};
-use std::io::{Read, Seek, BufReader, SeekFrom};
-use std::path::Path;
+use std::fs::{File, create_dir_all, remove_file, rename};
+use std::io::{self, Read, Write, Seek, BufReader, SeekFrom, Cursor};
+use std::path::{Path, PathBuf};
 
+/// blocks owned by the store.
+/// a block is only ever read from the store itself, never from the source it was inserted from
 pub struct BlockStore {
-    pub path:   String,
-    pub blocks: HashMap<Vec<u8>, Block>,
+    pub path:   Option<String>, //None keeps all content in memory
+    pub blocks: HashMap<Vec<u8>, Block>, //by tagged id, or by name when encrypted
+    pub hash:   HashAlgo, //for new blocks
+    pub crypt:  Option<Crypt>, //encrypt block content and names
 }
 
+/// a block held by the store
 #[derive(Debug)]
 pub struct Block {
+    pub content: BlockContent,
+    pub size: usize,
+}
+
+#[derive(Debug)]
+pub enum BlockContent {
+    File(OsString), //persisted in the store directory
+    Owned(Vec<u8>), //held in memory
+    Packed(OsString, u64), //at an offset of a pack, see pack
+}
+
+/// content that is about to be inserted into the store,
+/// composed of shards of host files which may change or disappear after insert
+#[derive(Debug)]
+pub struct PendingBlock {
     pub shards: Vec<BlockShard>,
     pub size: usize,
 }
@@ -25,131 +47,554 @@ pub struct BlockShard {
     pub size:    usize,
 }
 
-pub fn new(path: String) -> BlockStore {
+/// open the store persisted at path
+pub fn new(path: String) -> Result<BlockStore> {
     let mut bs = BlockStore{
-        path: path,
+        path: Some(path),
         blocks: HashMap::new(),
+        hash:   HashAlgo::Sha256,
+        crypt:  None,
     };
-    bs.load();
-    bs
+    try!(bs.load());
+    Ok(bs)
+}
+
+/// a store that keeps all block content in memory
+pub fn in_memory() -> BlockStore {
+    BlockStore{
+        path: None,
+        blocks: HashMap::new(),
+        hash:   HashAlgo::Sha256,
+        crypt:  None,
+    }
 }
 
 
 impl BlockStore {
+    /// what a block is kept under. untagged ids from older indexes are sha256
+    fn key(&self, hash: &[u8]) -> Vec<u8> {
+        match self.crypt {
+            None => hash::normalize(hash),
+            Some(ref crypt) => crypt.name(hash),
+        }
+    }
+
+    /// a block by id
     pub fn get<'a>(&'a self, hash: &Vec<u8>) -> Option<&'a Block> {
-        self.blocks.get(hash)
+        self.blocks.get(&self.key(hash))
+    }
+
+    /// the content of a block by id, decrypted if the store is encrypted
+    pub fn read<'a>(&'a self, hash: &Vec<u8>) -> Result<BlockReader<'a>> {
+        match self.get(hash) {
+            None => Err(Error::MissingBlock(hash.clone())),
+            Some(block) => self.open(hash, block),
+        }
+    }
+
+    fn open<'a>(&'a self, hash: &Vec<u8>, block: &'a Block) -> Result<BlockReader<'a>> {
+        let mut re = try!(block.reader().map_err(|e| Error::Io(format!("cannot open block {}", hash.to_hex()), e)));
+        if self.crypt.is_none() {
+            return Ok(re);
+        }
+        let mut sealed = Vec::with_capacity(block.size);
+        try!(re.read_to_end(&mut sealed).map_err(|e| Error::Io(format!("cannot read block {}", hash.to_hex()), e)));
+        self.unseal(hash, sealed).map(|content| BlockReader::Plain(Cursor::new(content)))
+    }
+
+    /// the content of a block from what its file holds, decrypted if the store is encrypted
+    pub fn unseal(&self, hash: &Vec<u8>, content: Vec<u8>) -> Result<Vec<u8>> {
+        match self.crypt {
+            None => Ok(content),
+            Some(ref crypt) => crypt.open(hash, content),
+        }
+    }
+
+    /// whether the block exists and its content matches its id
+    pub fn check(&self, hash: &Vec<u8>) -> bool {
+        let id = hash::normalize(hash);
+        let algo = match HashAlgo::of(&id) {
+            None => return false,
+            Some(algo) => algo,
+        };
+        match self.read(&id) {
+            Ok(content) => hash::digest_reader(algo, &mut BufReader::new(content)).ok() == Some(id),
+            Err(_) => false,
+        }
     }
-    pub fn insert(&mut self, hash: Vec<u8>, block: Block) -> bool {
+
+    /// copy a pending block into the store. returns false if the store already had it
+    pub fn insert(&mut self, hash: Vec<u8>, block: PendingBlock) -> Result<bool> {
         //sanity check on hash
         #[cfg(debug_assertions)]
         {
-            let mut br = BufReader::new(block.chain());
-            let hs = Sha256::digest_reader(&mut br).unwrap().as_slice().to_vec();
-            if hs != hash {
-
-                let mut br = BufReader::new(block.chain());
-                let mut content = Vec::new();
-                let rs = br.read_to_end(&mut content).unwrap();
-
-                if rs != block.size {
-                    panic!(format!("BUG: block should be {} bytes but did read {}", block.size, content.len()));
-                }
+            let algo = HashAlgo::of(&hash).expect("BUG: inserted block id is not tagged");
+            let mut content = Vec::new();
+            try!(try!(block.chain()).read_to_end(&mut content)
+                 .map_err(|e| Error::Io("cannot read inserted block".to_owned(), e)));
+            // the host files a pending block is read from may have changed since they were chunked
+            if content.len() != block.size || hash::digest(algo, &content) != hash {
+                return Err(Error::CorruptBlock(hash));
+            }
+        }
 
+        //collision check
+        if self.blocks.contains_key(&self.key(&hash)) {
+            try!(self.check_collision(&hash, try!(block.chain())));
+            return Ok(false);
+        }
 
-                let hs2 = Sha256::digest(&content).as_slice().to_vec();
-                if hs2 != hs2 {
-                    panic!("BUG: in chainreader: hash from read_to_end doesn't match digest_reader");
-                }
+        try!(self.persist(hash, block.size, try!(block.chain())));
+        Ok(true)
+    }
 
+    /// insert a block from memory, for content that has no host file shards could point at
+    pub fn insert_bytes(&mut self, hash: Vec<u8>, content: &[u8]) -> Result<bool> {
+        //sanity check on hash
+        #[cfg(debug_assertions)]
+        {
+            let algo = HashAlgo::of(&hash).expect("BUG: inserted block id is not tagged");
+            let hs = hash::digest(algo, content);
+            if hs != hash {
                 panic!(format!("BUG: inserted block hash id doesn't match its content. expected {} got {}", hash.to_hex(), hs.to_hex()));
             }
         }
 
-        //collision check
-        if self.blocks.contains_key(&hash) {
-            let mut ra = BufReader::new(block.chain());
-            let mut rb = BufReader::new(self.blocks[&hash].chain());
-            loop {
-                let mut a: [u8;4096] = [0; 4096];
-                let mut b: [u8;4096] = [0; 4096];
-                ra.read(&mut a).unwrap();
-                let rs = rb.read(&mut b).unwrap();
-
-                if a[..] != b[..] {
-                    println!("!!!!!! HASH COLLISION !!!!!!!!!!!!!!!!!!!!!");
-                    println!("this is extremly unlikely and might be a bug, save your block store for research.");
-                    println!("{}", hash.to_hex());
-                    panic!("hash collision");
-                }
+        if self.blocks.contains_key(&self.key(&hash)) {
+            try!(self.check_collision(&hash, content));
+            return Ok(false);
+        }
 
-                if rs < 1 {
-                    break;
-                }
+        try!(self.persist(hash, content.len(), content));
+        Ok(true)
+    }
+
+    fn check_collision<R: Read>(&self, hash: &Vec<u8>, content: R) -> Result<()> {
+        let mut ra = BufReader::new(content);
+        let mut rb = BufReader::new(try!(self.read(hash)));
+        loop {
+            let mut a: [u8;4096] = [0; 4096];
+            let mut b: [u8;4096] = [0; 4096];
+            try!(ra.read(&mut a).map_err(|e| Error::Io("cannot read inserted block".to_owned(), e)));
+            let rs = try!(rb.read(&mut b).map_err(|e| Error::Io(format!("cannot read block {}", hash.to_hex()), e)));
+
+            if a[..] != b[..] {
+                println!("!!!!!! HASH COLLISION !!!!!!!!!!!!!!!!!!!!!");
+                println!("this is extremly unlikely and might be a bug, save your block store for research.");
+                println!("{}", hash.to_hex());
+                panic!("hash collision");
             }
-            return false;
+
+            if rs < 1 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn persist<R: Read>(&mut self, hash: Vec<u8>, size: usize, mut content: R) -> Result<()> {
+        let sealed = match self.crypt {
+            None => None,
+            Some(ref crypt) => {
+                let mut buf = Vec::with_capacity(size);
+                try!(content.read_to_end(&mut buf).map_err(|e| Error::Io("cannot read inserted block".to_owned(), e)));
+                Some((crypt.name(&hash), crypt.seal(&hash, buf)))
+            },
+        };
+        match sealed {
+            None => self.write(hash, size, content),
+            Some((name, sealed)) => self.write(name, sealed.len(), &sealed[..]),
         }
+    }
 
-        //TODO sometimes we want to store the original block rather than saving it to disk
-        //the current interface will be weird later
+    fn write<R: Read>(&mut self, key: Vec<u8>, size: usize, mut content: R) -> Result<()> {
+        let path = match self.path {
+            None => {
+                let mut buf = Vec::with_capacity(size);
+                try!(content.read_to_end(&mut buf).map_err(|e| Error::Io("cannot read inserted block".to_owned(), e)));
+                self.blocks.insert(key, Block{
+                    size:    size,
+                    content: BlockContent::Owned(buf),
+                });
+                return Ok(());
+            },
+            Some(ref path) => path.clone(),
+        };
 
-        let hs = hash.to_hex();
-        let mut p = Path::new(&self.path).join(&hs[0..2]);
-        create_dir_all(&p).unwrap();
-        p = p.join(&hs[2..]);
+        // content/<first byte of the digest>/<tagged id or name>
+        let mut p = Path::new(&path).join(format!("{:02x}", hash::digest_part(&key)[0]));
+        try!(create_dir_all(&p).map_err(|e| Error::Io(format!("cannot create {}", p.display()), e)));
+        p = p.join(key.to_hex());
         if p.exists() {
             //TODO collision check?
         } else {
             //TODO: write to tempfile then move to avoid half written entries
-            let mut f = File::create(&p).unwrap();
-            ::std::io::copy(&mut block.chain(), &mut f).unwrap();
+            try!(File::create(&p).and_then(|mut f| io::copy(&mut content, &mut f))
+                 .map_err(|e| Error::Io(format!("cannot write {}", p.display()), e)));
         }
 
-        self.blocks.insert(hash, Block{
-            size: block.size,
-            shards: vec![
-                BlockShard {
-                    file:    OsString::from(p.to_str().unwrap()),
-                    offset:  0,
-                    size:    block.size,
-                }
-            ]
+        self.blocks.insert(key, Block{
+            size:    size,
+            content: BlockContent::File(p.into_os_string()),
         });
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<()> {
+        let path = self.path.clone().unwrap();
+        println!("loading content from {}", path);
+        let list = |p: &Path| ::std::fs::read_dir(p).and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+            .map_err(|e| Error::Io(format!("cannot list {}", p.display()), e));
+        for entry in try!(list(Path::new(&path))) {
+            if entry.file_name() == "packs" {
+                continue;
+            }
+            for entry2 in try!(list(&entry.path())) {
+                let name = entry2.file_name().to_string_lossy().into_owned();
+                let hash = if name.len() == 62 {
+                    //legacy sha256 layout, the directory holds the first byte
+                    Vec::<u8>::from_hex(entry.file_name().to_string_lossy().into_owned() + &name).map(|h| hash::normalize(&h))
+                } else {
+                    Vec::<u8>::from_hex(name)
+                };
+                let hash = try!(hash.map_err(|_| Error::Invalid(format!("{} is not a block", entry2.path().display()))));
+                let size = try!(entry2.metadata().map_err(|e| Error::Io(format!("cannot stat {}", entry2.path().display()), e))).len() as usize;
 
-        return true;
-    }
-
-    fn load(&mut self) {
-        println!("loading content from {}", self.path);
-        let entry_set = ::std::fs::read_dir(&self.path).unwrap();
-        for entry in entry_set {
-            let entry = entry.unwrap();
-            let entry_set2 = ::std::fs::read_dir(entry.path()).unwrap();
-            for entry2 in entry_set2 {
-                let entry2 = entry2.unwrap();
-                let hash = entry.file_name().to_string_lossy().into_owned() + &entry2.file_name().to_string_lossy().into_owned();
-                let hash = Vec::<u8>::from_hex(hash).unwrap();
-                let size = entry2.metadata().unwrap().len() as usize;
-
-                self.insert(hash, Block {
-                    shards: vec![BlockShard{
-                        file:    entry2.path().into_os_string(),
-                        offset:  0,
-                        size:    size,
-                    }],
-                    size: size,
+                self.blocks.insert(hash, Block {
+                    content: BlockContent::File(entry2.path().into_os_string()),
+                    size:    size,
                 });
             }
         }
+
+        // packed blocks take precedence over loose copies
+        let packs = Path::new(&path).join("packs");
+        if packs.is_dir() {
+            let mut entries = try!(list(&packs));
+            entries.sort_by_key(|e| e.file_name());
+            for entry in entries {
+                if entry.path().extension().map(|e| e == "idx").unwrap_or(false) {
+                    try!(self.load_pack(&entry.path()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// the pack file a pack index lists, and its blocks
+    fn pack_index(&self, idx: &Path) -> Result<(PathBuf, Vec<(Vec<u8>, u64, usize)>)> {
+        let mut content = String::new();
+        try!(File::open(idx).and_then(|mut f| f.read_to_string(&mut content))
+             .map_err(|e| Error::Io(format!("cannot read {}", idx.display()), e)));
+        let mut lines = content.lines();
+        let pack = match lines.next() {
+            Some(file) if !file.is_empty() && !file.contains('/') => idx.with_file_name(file),
+            _ => return Err(Error::Invalid(format!("{} names no pack", idx.display()))),
+        };
+        let mut r = Vec::new();
+        for line in lines {
+            let parts: Vec<&str> = line.split(' ').collect();
+            let entry = match parts.len() {
+                3 => Vec::<u8>::from_hex(parts[0]).ok().and_then(|key| {
+                    parts[1].parse().ok().and_then(|offset| parts[2].parse().ok().map(|size| (key, offset, size)))
+                }),
+                _ => None,
+            };
+            match entry {
+                None => return Err(Error::Invalid(format!("{} has a bad entry {:?}", idx.display(), line))),
+                Some(entry) => r.push(entry),
+            }
+        }
+        Ok((pack, r))
+    }
+
+    fn load_pack(&mut self, idx: &Path) -> Result<()> {
+        let (pack, entries) = try!(self.pack_index(idx));
+        for (key, offset, size) in entries {
+            self.blocks.insert(key, Block {
+                content: BlockContent::Packed(pack.as_os_str().to_owned(), offset),
+                size:    size,
+            });
+        }
+        Ok(())
+    }
+
+    /// store blocks one after the other in a pack, so reading them in that order is sequential.
+    /// the pack of a name replaces an earlier one of that name. content/packs/<name>.idx names
+    /// the pack file, <name>-<id of its content>, and then lists one block per line:
+    ///
+    ///   <tagged id, or name when encrypted> <offset> <size>
+    ///
+    /// the index is renamed into place only once everything it points at is written, and files
+    /// it no longer points at are removed only after that, so a crash leaves the old pack or the
+    /// new one. blocks move under anything reading the store, so it must not be mounted meanwhile.
+    /// returns the number of blocks packed
+    pub fn pack(&mut self, name: &str, ids: &[Vec<u8>]) -> Result<usize> {
+        let packs = match self.path {
+            None => return Err(Error::Invalid("a store in memory has no packs".to_owned())),
+            Some(ref path) => Path::new(path).join("packs"),
+        };
+        try!(create_dir_all(&packs).map_err(|e| Error::Io(format!("cannot create {}", packs.display()), e)));
+        let idx_path = packs.join(name.to_owned() + ".idx");
+
+        let mut keys = Vec::new();
+        let mut seen = ::std::collections::HashSet::new();
+        for id in ids {
+            let key = self.key(id);
+            if !self.blocks.contains_key(&key) {
+                return Err(Error::MissingBlock(id.clone()));
+            }
+            if seen.insert(key.clone()) {
+                keys.push(key);
+            }
+        }
+
+        // as stored, so encrypted blocks stay sealed
+        let tmp = packs.join(name.to_owned() + ".new");
+        let mut idx = String::new();
+        let mut h = hash::Hasher::new(self.hash);
+        {
+            let mut f = try!(File::create(&tmp).map_err(|e| Error::Io(format!("cannot create {}", tmp.display()), e)));
+            let mut offset = 0;
+            for key in &keys {
+                let block = &self.blocks[key];
+                let mut content = Vec::with_capacity(block.size);
+                try!(block.reader().and_then(|mut re| re.read_to_end(&mut content)).and_then(|_| f.write_all(&content))
+                     .map_err(|e| Error::Io(format!("cannot pack block {}", key.to_hex()), e)));
+                h.input(&content);
+                idx += &format!("{} {} {}\n", key.to_hex(), offset, block.size);
+                offset += block.size as u64;
+            }
+            try!(f.sync_all().map_err(|e| Error::Io(format!("cannot write {}", tmp.display()), e)));
+        }
+        let file = format!("{}-{}", name, h.result().to_hex());
+        let pack = packs.join(&file);
+        try!(rename(&tmp, &pack).map_err(|e| Error::Io(format!("cannot write {}", pack.display()), e)));
+
+        // blocks of the pack being replaced that aren't in the new one go back to their own files
+        let old = match idx_path.exists() {
+            true  => Some(try!(self.pack_index(&idx_path)).0),
+            false => None,
+        };
+        let left: Vec<Vec<u8>> = self.blocks.iter().filter(|&(k, b)| match b.content {
+            BlockContent::Packed(ref p, _) => old.as_ref().map(|old| Path::new(p) == old).unwrap_or(false) && !seen.contains(k),
+            _ => false,
+        }).map(|(k, _)| k.clone()).collect();
+        for key in left {
+            let (size, content) = {
+                let block = &self.blocks[&key];
+                let mut content = Vec::with_capacity(block.size);
+                try!(block.reader().and_then(|mut re| re.read_to_end(&mut content))
+                     .map_err(|e| Error::Io(format!("cannot read block {}", key.to_hex()), e)));
+                (block.size, content)
+            };
+            self.blocks.remove(&key);
+            try!(self.write(key, size, &content[..]));
+        }
+
+        let tmp = packs.join(name.to_owned() + ".idx.new");
+        try!(File::create(&tmp).and_then(|mut f| f.write_all(format!("{}\n{}", file, idx).as_bytes()).and_then(|_| f.sync_all()))
+             .map_err(|e| Error::Io(format!("cannot write {}", tmp.display()), e)));
+        try!(rename(&tmp, &idx_path).map_err(|e| Error::Io(format!("cannot write {}", idx_path.display()), e)));
+
+        // nothing points at loose copies and older packs of this name anymore
+        let mut unused: Vec<PathBuf> = keys.iter().filter_map(|k| match self.blocks[k].content {
+            BlockContent::File(ref p) => Some(PathBuf::from(p)),
+            _ => None,
+        }).collect();
+        try!(self.load_pack(&idx_path));
+        let entries = try!(::std::fs::read_dir(&packs).and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+                           .map_err(|e| Error::Io(format!("cannot list {}", packs.display()), e)));
+        let prefix = name.to_owned() + "-";
+        unused.extend(entries.into_iter().map(|e| e.file_name().to_string_lossy().into_owned()).filter(|f| {
+            *f != file && f.starts_with(&prefix) && Vec::<u8>::from_hex(&f[prefix.len()..]).is_ok()
+        }).map(|f| packs.join(f)));
+        for p in unused {
+            try!(remove_file(&p).map_err(|e| Error::Io(format!("cannot remove {}", p.display()), e)));
+        }
+        Ok(keys.len())
     }
 }
 
 impl Block {
-    pub fn chain<'a>(&'a self) -> Chain<'a, Take<File>> {
-        let it = self.shards.iter().map(|shard| {
-            let mut f = File::open(&shard.file).unwrap();
-            f.seek(SeekFrom::Current(shard.offset as i64)).unwrap();
-            Take::limit(f, shard.size)
-        });
-        Chain::new(Box::new(it))
+    pub fn reader<'a>(&'a self) -> io::Result<BlockReader<'a>> {
+        match self.content {
+            BlockContent::File(ref path) => File::open(path).map(BlockReader::File),
+            BlockContent::Owned(ref buf) => Ok(BlockReader::Owned(Cursor::new(&buf[..]))),
+            BlockContent::Packed(ref path, offset) => {
+                let mut content = vec![0; self.size];
+                try!(File::open(path).and_then(|mut f| f.seek(SeekFrom::Start(offset)).and_then(|_| f.read_exact(&mut content))));
+                Ok(BlockReader::Plain(Cursor::new(content)))
+            },
+        }
+    }
+}
+
+pub enum BlockReader<'a> {
+    File(File),
+    Owned(Cursor<&'a [u8]>),
+    Zeros(u64), //endless zeros for holes, at a position
+    Plain(Cursor<Vec<u8>>), //decrypted content
+    Failed(String), //a block that could not be read, failing every read instead of the whole file
+}
+
+impl<'a> BlockReader<'a> {
+    fn failed(e: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, e.to_owned())
+    }
+}
+
+impl<'a> Read for BlockReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            BlockReader::File(ref mut f)  => f.read(buf),
+            BlockReader::Owned(ref mut c) => c.read(buf),
+            BlockReader::Plain(ref mut c) => c.read(buf),
+            BlockReader::Failed(ref e)    => Err(BlockReader::failed(e)),
+            BlockReader::Zeros(ref mut pos) => {
+                for b in buf.iter_mut() {
+                    *b = 0;
+                }
+                *pos += buf.len() as u64;
+                Ok(buf.len())
+            },
+        }
+    }
+}
+
+impl<'a> Seek for BlockReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match *self {
+            BlockReader::File(ref mut f)  => f.seek(pos),
+            BlockReader::Owned(ref mut c) => c.seek(pos),
+            BlockReader::Plain(ref mut c) => c.seek(pos),
+            BlockReader::Failed(ref e)    => Err(BlockReader::failed(e)),
+            BlockReader::Zeros(ref mut at) => {
+                match pos {
+                    SeekFrom::Start(o)   => *at = o,
+                    SeekFrom::Current(o) => *at = (*at as i64 + o) as u64,
+                    SeekFrom::End(_) => {
+                        return Err(io::Error::new(io::ErrorKind::NotFound, "cannot seek end on zeros"));
+                    },
+                }
+                Ok(*at)
+            },
+        }
+    }
+}
+
+impl PendingBlock {
+    /// the content, with every shard opened up front so a vanished host file is an error here
+    pub fn chain<'a>(&'a self) -> Result<Chain<'a, Take<File>>> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            let f = try!(File::open(&shard.file).and_then(|mut f| f.seek(SeekFrom::Start(shard.offset as u64)).map(|_| f))
+                             .map_err(|e| Error::Io(format!("cannot read {:?}", shard.file), e)));
+            shards.push(Take::limit(f, shard.size));
+        }
+        Ok(Chain::new(Box::new(shards.into_iter())))
+    }
+}
+
+
+#[test]
+fn read_after_insert_is_served_from_store() {
+    let dir = ::testing::temp_dir("blockstore");
+    let enc = ::testing::temp_dir("blockstore-enc");
+    let mut encrypted = new(enc.to_str().unwrap().to_owned()).unwrap();
+    encrypted.crypt = Some(Crypt::new(vec![1; 32]));
+
+    for mut bs in vec![in_memory(), new(dir.to_str().unwrap().to_owned()).unwrap(), encrypted] {
+        let mut source = ::tempfile::NamedTempFile::new().unwrap();
+        source.write_all(b"hello world").unwrap();
+
+        let hash = hash::digest(HashAlgo::Blake3, b"world");
+        assert!(bs.insert(hash.clone(), PendingBlock{
+            shards: vec![BlockShard{
+                file:   source.path().as_os_str().to_owned(),
+                offset: 6,
+                size:   5,
+            }],
+            size: 5,
+        }).unwrap());
+
+        source.seek(SeekFrom::Start(0)).unwrap();
+        source.write_all(b"changed!!!!").unwrap();
+
+        let mut content = String::new();
+        bs.read(&hash).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "world");
+    }
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn pending_blocks_that_changed_are_errors() {
+    let mut source = ::tempfile::NamedTempFile::new().unwrap();
+    source.write_all(b"hello").unwrap();
+    let hash = hash::digest(HashAlgo::Sha256, b"world");
+    match in_memory().insert(hash.clone(), PendingBlock{
+        shards: vec![BlockShard{
+            file:   source.path().as_os_str().to_owned(),
+            offset: 0,
+            size:   5,
+        }],
+        size: 5,
+    }) {
+        Err(Error::CorruptBlock(h)) => assert_eq!(h, hash),
+        _ => panic!("expected a corrupt block"),
+    }
+}
+
+#[test]
+fn missing_and_undecryptable_blocks_are_errors() {
+    let hash = hash::digest(HashAlgo::Sha256, b"hello");
+    match in_memory().read(&hash) {
+        Err(Error::MissingBlock(h)) => assert_eq!(h, hash),
+        _ => panic!("expected a missing block"),
     }
+
+    let mut bs = in_memory();
+    bs.crypt = Some(Crypt::new(vec![1; 32]));
+    bs.insert_bytes(hash.clone(), b"hello").unwrap();
+    bs.crypt = Some(Crypt::new(vec![2; 32]));
+    let name = Crypt::new(vec![1; 32]).name(&hash);
+    let sealed = bs.blocks.remove(&name).unwrap();
+    bs.blocks.insert(bs.key(&hash), sealed);
+    match bs.read(&hash) {
+        Err(Error::CorruptBlock(_)) => {},
+        _ => panic!("expected a corrupt block"),
+    }
+}
+
+#[test]
+fn packed_blocks_read_like_loose_ones() {
+    let dir = ::testing::temp_dir("blockstore-pack");
+    let mut bs = new(dir.to_str().unwrap().to_owned()).unwrap();
+    let ids: Vec<Vec<u8>> = [&b"aaaa"[..], b"bbbbbb", b"cc"].iter().map(|c| {
+        let id = hash::digest(bs.hash, c);
+        bs.insert_bytes(id.clone(), c).unwrap();
+        id
+    }).collect();
+
+    assert_eq!(bs.pack("boot", &[ids[2].clone(), ids[0].clone(), ids[2].clone()]).unwrap(), 2);
+    let read = |bs: &BlockStore, id: &Vec<u8>| {
+        let mut content = Vec::new();
+        bs.read(id).unwrap().read_to_end(&mut content).unwrap();
+        content
+    };
+    let bs = new(dir.to_str().unwrap().to_owned()).unwrap();
+    assert_eq!(read(&bs, &ids[0]), b"aaaa");
+    assert_eq!(read(&bs, &ids[2]), b"cc");
+    assert!(bs.check(&ids[1]));
+    match bs.get(&ids[0]).unwrap().content {
+        BlockContent::Packed(_, offset) => assert_eq!(offset, 2),
+        ref c => panic!("expected a packed block, got {:?}", c),
+    }
+
+    // a block left out of the new pack is kept on its own, and the old pack is removed
+    let mut bs = bs;
+    bs.pack("boot", &[ids[1].clone()]).unwrap();
+    let bs = new(dir.to_str().unwrap().to_owned()).unwrap();
+    assert!(ids.iter().all(|id| bs.check(id)));
+    assert_eq!(::std::fs::read_dir(dir.join("packs")).unwrap().count(), 2);
 }
@@ -0,0 +1,381 @@
+use blockstore::{BlockStore, BlockContent};
+use crypt::Crypt;
+use error::{Error, Result};
+use hash::{self, HashAlgo};
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+/// bytes of block content kept by default
+pub const DEFAULT_SIZE: usize = 64 * 1024 * 1024;
+/// block files kept open by default
+pub const DEFAULT_FILES: usize = 256;
+
+/// a map that drops its least recently used entries once their weight exceeds a limit
+struct Lru<K, V> {
+    entries: HashMap<K, (V, u64, usize)>, //value, last use, weight
+    uses:    BTreeMap<u64, K>,
+    tick:    u64,
+    weight:  usize,
+    limit:   usize,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    fn new(limit: usize) -> Lru<K, V> {
+        Lru {
+            entries: HashMap::new(),
+            uses:    BTreeMap::new(),
+            tick:    0,
+            weight:  0,
+            limit:   limit,
+        }
+    }
+
+    fn get(&mut self, k: &K) -> Option<&mut V> {
+        self.tick += 1;
+        match self.entries.get_mut(k) {
+            None => None,
+            Some(&mut (ref mut v, ref mut used, _)) => {
+                self.uses.remove(used);
+                self.uses.insert(self.tick, k.clone());
+                *used = self.tick;
+                Some(v)
+            },
+        }
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        self.entries.remove(k).map(|(v, used, w)| {
+            self.uses.remove(&used);
+            self.weight -= w;
+            v
+        })
+    }
+
+    /// returns how many entries were dropped to make room.
+    /// an entry heavier than the limit is not kept at all
+    fn insert(&mut self, k: K, v: V, weight: usize) -> u64 {
+        if weight > self.limit {
+            return 0;
+        }
+        self.tick += 1;
+        if let Some((_, used, w)) = self.entries.insert(k.clone(), (v, self.tick, weight)) {
+            self.uses.remove(&used);
+            self.weight -= w;
+        }
+        self.uses.insert(self.tick, k);
+        self.weight += weight;
+
+        let mut evicted = 0;
+        while self.weight > self.limit {
+            let oldest = *self.uses.keys().next().unwrap();
+            let k = self.uses.remove(&oldest).unwrap();
+            let (_, _, w) = self.entries.remove(&k).unwrap();
+            self.weight -= w;
+            evicted += 1;
+        }
+        evicted
+    }
+}
+
+/// how well the cache did
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Stats {
+    pub hits:    u64, //blocks served from the cache
+    pub misses:  u64, //blocks read from the store
+    pub evicted: u64, //blocks dropped to make room
+    pub reused:  u64, //block files read through an open descriptor
+    pub opened:  u64, //block files opened
+    pub prefetched: u64, //blocks loaded in the background
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ratio = |a: u64, b: u64| if a + b == 0 { 0.0 } else { 100.0 * a as f64 / (a + b) as f64 };
+        write!(f, "block cache: {} hits, {} misses ({:.1}% hit), {} evicted, {} prefetched. block files: {} reused, {} opened ({:.1}% reused)",
+               self.hits, self.misses, ratio(self.hits, self.misses), self.evicted, self.prefetched,
+               self.reused, self.opened, ratio(self.reused, self.opened))
+    }
+}
+
+/// what the cache shares with its prefetch threads
+struct Shared {
+    blocks:     Lru<Vec<u8>, Arc<Vec<u8>>>, //by normalized id
+    pending:    HashSet<Vec<u8>>, //queued for prefetching
+    evicted:    u64,
+    prefetched: u64,
+}
+
+impl Shared {
+    fn insert(&mut self, key: Vec<u8>, content: Arc<Vec<u8>>) {
+        let size = content.len();
+        self.evicted += self.blocks.insert(key, content, size);
+    }
+}
+
+/// a block to load in the background
+struct Fetch {
+    key:    Vec<u8>,
+    id:     Vec<u8>,
+    path:   OsString,
+    offset: u64,
+    size:   usize,
+}
+
+/// the file a block is in, and where in it
+fn location(content: &BlockContent) -> Option<(&OsString, u64)> {
+    match *content {
+        BlockContent::File(ref path) => Some((path, 0)),
+        BlockContent::Packed(ref path, offset) => Some((path, offset)),
+        BlockContent::Owned(_) => None,
+    }
+}
+
+fn read_at(f: &mut File, offset: u64, size: usize) -> ::std::io::Result<Vec<u8>> {
+    let mut content = vec![0; size];
+    try!(f.seek(SeekFrom::Start(offset)));
+    try!(f.read_exact(&mut content));
+    Ok(content)
+}
+
+/// decoded block content and open block files, shared by every file of a mount
+/// and every thread reading them
+pub struct Cache {
+    shared:   Arc<Mutex<Shared>>,
+    files:    Mutex<Lru<OsString, File>>,
+    stats:    Mutex<Stats>,
+    prefetch: Mutex<Option<Sender<Fetch>>>,
+}
+
+/// a cache of up to size bytes of block content and files open block files
+pub fn new(size: usize, files: usize) -> Cache {
+    Cache {
+        shared: Arc::new(Mutex::new(Shared {
+            blocks:     Lru::new(size),
+            pending:    HashSet::new(),
+            evicted:    0,
+            prefetched: 0,
+        })),
+        files:    Mutex::new(Lru::new(files)),
+        stats:    Mutex::new(Stats::default()),
+        prefetch: Mutex::new(None),
+    }
+}
+
+/// the content of a block, unless it doesn't match the hash in its id
+fn verified(id: &[u8], content: Vec<u8>) -> Result<Vec<u8>> {
+    let id = hash::normalize(id);
+    match HashAlgo::of(&id) {
+        Some(algo) if hash::digest(algo, &content) == id => Ok(content),
+        _ => Err(Error::CorruptBlock(id)),
+    }
+}
+
+/// the content of a block in the background
+fn fetch(f: &Fetch, crypt: Option<&Crypt>) -> Result<Vec<u8>> {
+    let content = try!(File::open(&f.path).and_then(|mut file| read_at(&mut file, f.offset, f.size))
+                       .map_err(|e| Error::Io(format!("cannot read block {:?}", f.path), e)));
+    let content = match crypt {
+        None => content,
+        Some(crypt) => try!(crypt.open(&f.id, content)),
+    };
+    verified(&f.id, content)
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        new(DEFAULT_SIZE, DEFAULT_FILES)
+    }
+}
+
+impl Cache {
+    pub fn stats(&self) -> Stats {
+        let shared = self.shared.lock().unwrap();
+        Stats {
+            evicted:    shared.evicted,
+            prefetched: shared.prefetched,
+            ..*self.stats.lock().unwrap()
+        }
+    }
+
+    /// the decrypted content of a block
+    pub fn block(&self, blockstore: &BlockStore, id: &Vec<u8>) -> Result<Arc<Vec<u8>>> {
+        let key = hash::normalize(id);
+        if let Some(content) = self.shared.lock().unwrap().blocks.get(&key) {
+            self.stats.lock().unwrap().hits += 1;
+            return Ok(content.clone());
+        }
+        self.stats.lock().unwrap().misses += 1;
+
+        let content = Arc::new(try!(self.load(blockstore, id)));
+        self.shared.lock().unwrap().insert(key, content.clone());
+        Ok(content)
+    }
+
+    /// start threads that load blocks queued by prefetch into the cache
+    pub fn start_prefetch(&mut self, blockstore: &BlockStore, threads: usize) {
+        if threads == 0 {
+            return;
+        }
+        let (tx, rx) = channel::<Fetch>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..threads {
+            let rx     = rx.clone();
+            let shared = self.shared.clone();
+            let crypt  = blockstore.crypt.clone();
+            thread::spawn(move || {
+                loop {
+                    let next = rx.lock().unwrap().recv();
+                    let f = match next {
+                        Err(_) => return, //the cache is gone
+                        Ok(f) => f,
+                    };
+                    let r = fetch(&f, crypt.as_ref());
+                    let mut shared = shared.lock().unwrap();
+                    shared.pending.remove(&f.key);
+                    match r {
+                        Err(e) => println!("prefetch: {}", e),
+                        Ok(content) => {
+                            shared.prefetched += 1;
+                            shared.insert(f.key, Arc::new(content));
+                        },
+                    }
+                }
+            });
+        }
+        *self.prefetch.lock().unwrap() = Some(tx);
+    }
+
+    /// load blocks in the background, unless they are cached or queued already.
+    /// does nothing unless prefetching was started
+    pub fn prefetch<'a, I: IntoIterator<Item=&'a Vec<u8>>>(&self, blockstore: &BlockStore, ids: I) {
+        let tx = self.prefetch.lock().unwrap();
+        let tx = match *tx {
+            None => return,
+            Some(ref tx) => tx,
+        };
+        let mut shared = self.shared.lock().unwrap();
+        for id in ids {
+            let key = hash::normalize(id);
+            if shared.pending.contains(&key) || shared.blocks.entries.contains_key(&key) {
+                continue;
+            }
+            // blocks in memory are as fast to get when they are read
+            let block = match blockstore.get(id) {
+                None => continue,
+                Some(block) => block,
+            };
+            let (path, offset) = match location(&block.content) {
+                None => continue,
+                Some(at) => at,
+            };
+            shared.pending.insert(key.clone());
+            tx.send(Fetch {
+                key:    key,
+                id:     id.clone(),
+                path:   path.clone(),
+                offset: offset,
+                size:   block.size,
+            }).unwrap();
+        }
+    }
+
+    fn load(&self, blockstore: &BlockStore, id: &Vec<u8>) -> Result<Vec<u8>> {
+        let block = match blockstore.get(id) {
+            None => return Err(Error::MissingBlock(id.clone())),
+            Some(block) => block,
+        };
+        let (path, offset) = match block.content {
+            BlockContent::Owned(ref buf) => return blockstore.unseal(id, buf.clone()).and_then(|c| verified(id, c)),
+            ref content => location(content).unwrap(),
+        };
+
+        // blocks of a pack share its descriptor. it is taken out while reading,
+        // so another thread reading the same file opens its own
+        let pooled = self.files.lock().unwrap().remove(path);
+        let f = match pooled {
+            Some(f) => {
+                self.stats.lock().unwrap().reused += 1;
+                Ok(f)
+            },
+            None => {
+                self.stats.lock().unwrap().opened += 1;
+                File::open(path)
+            },
+        };
+        let r = f.and_then(|mut f| {
+            let r = read_at(&mut f, offset, block.size);
+            self.files.lock().unwrap().insert(path.clone(), f, 1);
+            r
+        });
+        let content = try!(r.map_err(|e| Error::Io(format!("cannot read block {:?}", path), e)));
+        verified(id, try!(blockstore.unseal(id, content)))
+    }
+}
+
+#[test]
+fn least_recently_used_blocks_are_dropped() {
+    let mut bs = ::blockstore::in_memory();
+    let ids: Vec<Vec<u8>> = [&b"aaaa"[..], b"bbbb", b"cccc"].iter().map(|c| {
+        let id = hash::digest(bs.hash, c);
+        bs.insert_bytes(id.clone(), c).unwrap();
+        id
+    }).collect();
+
+    let cache = new(8, 1);
+    assert_eq!(&cache.block(&bs, &ids[0]).unwrap()[..], b"aaaa");
+    cache.block(&bs, &ids[1]).unwrap();
+    cache.block(&bs, &ids[0]).unwrap();
+    cache.block(&bs, &ids[2]).unwrap();
+    assert_eq!((cache.stats().hits, cache.stats().misses, cache.stats().evicted), (1, 3, 1));
+
+    // b was used least recently
+    cache.block(&bs, &ids[0]).unwrap();
+    cache.block(&bs, &ids[1]).unwrap();
+    assert_eq!((cache.stats().hits, cache.stats().misses), (2, 4));
+    assert!(cache.block(&bs, &vec![0; 33]).is_err());
+}
+
+#[test]
+fn prefetched_blocks_are_hits() {
+    let dir = ::testing::temp_dir("cache");
+    let mut bs = ::blockstore::new(dir.to_str().unwrap().to_owned()).unwrap();
+    let ids: Vec<Vec<u8>> = [&b"aaaa"[..], b"bbbb"].iter().map(|c| {
+        let id = hash::digest(bs.hash, c);
+        bs.insert_bytes(id.clone(), c).unwrap();
+        id
+    }).collect();
+
+    let mut cache = new(1024, 1);
+    cache.start_prefetch(&bs, 2);
+    cache.prefetch(&bs, &ids);
+    while cache.stats().prefetched < 2 {
+        thread::sleep(::std::time::Duration::from_millis(1));
+    }
+    assert_eq!(&cache.block(&bs, &ids[1]).unwrap()[..], b"bbbb");
+    assert_eq!((cache.stats().hits, cache.stats().misses, cache.stats().opened), (1, 0, 0));
+}
+
+#[test]
+fn blocks_that_changed_on_disk_are_corrupt() {
+    use std::io::Write;
+    let dir = ::testing::temp_dir("cache-corrupt");
+    let mut bs = ::blockstore::new(dir.to_str().unwrap().to_owned()).unwrap();
+    let id = hash::digest(bs.hash, b"aaaa");
+    bs.insert_bytes(id.clone(), b"aaaa").unwrap();
+    let (path, offset) = location(&bs.get(&id).unwrap().content).map(|(p, o)| (p.clone(), o)).unwrap();
+    let mut f = ::std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.seek(SeekFrom::Start(offset)).unwrap();
+    f.write_all(b"aaab").unwrap();
+
+    match new(1024, 1).block(&bs, &id) {
+        Err(Error::CorruptBlock(ref h)) if *h == id => (),
+        r => panic!("expected a corrupt block, got {:?}", r.map(|_| ())),
+    }
+}
@@ -1,9 +1,23 @@
-use std::io::{Read};
-use sha2::{Sha256, Digest};
+use error::{Error, Result};
+use hash::{HashAlgo, Hasher};
+use rollsum::Engine;
+use std::cmp;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
 
 /// takes an iterator over tuple (Read, I)
-/// and provides an iterator over Chunk{hash, parts<I>}
+/// and provides an iterator over Chunk{hash, parts<I>}.
+/// a read that fails ends the iteration with the error
 ///
+/// by default all reads are chunked as one stream, so a block may span files.
+/// with per_file, every file ends its last block and starts with a fresh rollsum,
+/// so a file's blocks don't depend on its neighbours.
 pub struct Chunker<'a, R, C, I> where R : Read, C: ::rollsum::Engine {
     it: Box<Iterator<Item=(R, I)> + 'a>,
     current_read: Option<(R,I)>,
@@ -13,13 +27,16 @@ pub struct Chunker<'a, R, C, I> where R : Read, C: ::rollsum::Engine {
 
     chunker: C,
     bits: u32,
+    per_file: bool,
 
-    hasher: Sha256,
+    hasher: Hasher,
 
     buf: [u8;4096],
     buflen : usize,
     bufpos : usize,
     bufsincelastblock: usize,
+
+    failed: bool,
 }
 
 pub struct Chunk<I> {
@@ -35,8 +52,8 @@ pub struct ChunkPart<I> {
     pub block_start: usize, //where the block was when the file started
 }
 
-impl<'a, R, C, I> Chunker<'a, R, C, I> where I: Copy, R: Read, C: ::rollsum::Engine {
-    pub fn new(it: Box<Iterator<Item=(R, I)> + 'a>, c: C, bits: u32) -> Chunker<'a, R, C, I>{
+impl<'a, R, C, I> Chunker<'a, R, C, I> where I: Copy, R: Read, C: ::rollsum::Engine + Default {
+    pub fn new(it: Box<Iterator<Item=(R, I)> + 'a>, c: C, bits: u32, per_file: bool, algo: HashAlgo) -> Chunker<'a, R, C, I>{
         Chunker{
             it: it,
             current_read: None,
@@ -46,20 +63,23 @@ impl<'a, R, C, I> Chunker<'a, R, C, I> where I: Copy, R: Read, C: ::rollsum::Eng
 
             chunker: c,
             bits: bits,
+            per_file: per_file,
 
-            hasher: Sha256::default(),
+            hasher: Hasher::new(algo),
 
             buf: [0;4096],
             buflen: 0,
             bufpos: 0,
             bufsincelastblock: 0,
+
+            failed: false,
         }
     }
 
-    fn fill(&mut self) -> bool {
+    fn fill(&mut self) -> io::Result<bool> {
         if let None = self.current_read {
             match self.it.next() {
-                None => return false,
+                None => return Ok(false),
                 Some(r) => {
                     self.current_parts.push(ChunkPart{
                         i: r.1,
@@ -69,30 +89,36 @@ impl<'a, R, C, I> Chunker<'a, R, C, I> where I: Copy, R: Read, C: ::rollsum::Eng
                     });
                     self.current_file_pos = 0;
                     self.current_read = Some(r);
+                    if self.per_file {
+                        self.chunker = C::default();
+                    }
                 }
             }
         }
-        match self.current_read.as_mut().unwrap().0.read(&mut self.buf) {
-            Err(e) => panic!(e),
-            Ok(some) => {
-                if some < 1 {
-                    self.current_parts.last_mut().as_mut().unwrap().file_end = self.current_file_pos;
-                    self.current_read = None;
-                    return self.fill();
-                } else {
-                    self.buflen = some;
-                    return true;
-                }
+        let some = try!(self.current_read.as_mut().unwrap().0.read(&mut self.buf));
+        if some < 1 {
+            self.current_parts.last_mut().as_mut().unwrap().file_end = self.current_file_pos;
+            self.current_read = None;
+            if self.per_file {
+                return Ok(false);
             }
+            self.fill()
+        } else {
+            self.buflen = some;
+            Ok(true)
         }
     }
+
 }
 
 
-impl<'a, R, C, I> Iterator for Chunker<'a, R, C, I> where I: Copy, R: Read, C: ::rollsum::Engine<Digest = u32> {
-    type Item = Chunk<I>;
+impl<'a, R, C, I> Iterator for Chunker<'a, R, C, I> where I: Copy, R: Read, C: ::rollsum::Engine<Digest = u32> + Default {
+    type Item = Result<Chunk<I>>;
     fn next(&mut self) -> Option<Self::Item> {
         let chunk_mask = (1 << self.bits) - 1;
+        if self.failed {
+            return None;
+        }
         loop {
             if self.bufpos >= self.buflen {
 
@@ -104,16 +130,27 @@ impl<'a, R, C, I> Iterator for Chunker<'a, R, C, I> where I: Copy, R: Read, C: :
                 self.bufpos = 0;
                 self.buflen = 0;
 
-                if !self.fill() {
-                    //rest
+                let more = match self.fill() {
+                    Err(e) => {
+                        self.failed = true;
+                        return Some(Err(Error::Io("cannot read content to chunk".to_owned(), e)));
+                    },
+                    Ok(more) => more,
+                };
+                if !more {
+                    //rest of the stream, or of the file when chunking per file
+                    if self.per_file && file_ended_on_boundary(&self.current_parts, self.current_block_len) {
+                        self.current_parts.clear();
+                        continue;
+                    }
                     if self.current_parts.len() > 0 {
-                        let hash = self.hasher.result().as_slice().to_vec();
+                        let hash = self.hasher.reset();
                         self.current_parts.last_mut().as_mut().unwrap().file_end = self.current_file_pos;
-                        return Some(Chunk{
-                            len: self.current_block_len,
+                        return Some(Ok(Chunk{
+                            len: ::std::mem::replace(&mut self.current_block_len, 0),
                             hash: hash,
                             parts: ::std::mem::replace(&mut self.current_parts, Vec::new()),
-                        });
+                        }));
                     } else {
                         debug_assert!(self.bufsincelastblock == 0 && self.bufpos == 0, "end of iterator with leftover bytes");
                         return None;
@@ -132,8 +169,7 @@ impl<'a, R, C, I> Iterator for Chunker<'a, R, C, I> where I: Copy, R: Read, C: :
                 self.current_file_pos  += self.bufpos-self.bufsincelastblock;
                 self.hasher.input(&self.buf[self.bufsincelastblock..self.bufpos]);
 
-                let hash = self.hasher.result().as_slice().to_vec();
-                self.hasher = Sha256::default();
+                let hash = self.hasher.reset();
 
                 self.current_parts.last_mut().as_mut().unwrap().file_end = self.current_file_pos;
                 let rr = Chunk{
@@ -151,9 +187,357 @@ impl<'a, R, C, I> Iterator for Chunker<'a, R, C, I> where I: Copy, R: Read, C: :
                 self.current_block_len = 0;
                 self.bufsincelastblock = self.bufpos;
 
-                return Some(rr);
+                return Some(Ok(rr));
+            }
+        }
+    }
+}
+
+
+
+/// the last block of the file ended exactly at its end, and what's left is an empty part
+fn file_ended_on_boundary<I>(parts: &Vec<ChunkPart<I>>, block_len: usize) -> bool {
+    block_len == 0 && parts.len() == 1 && parts[0].file_start > 0
+}
+
+/// boundaries and hashes a worker found in a single file
+struct FileCuts {
+    len:    usize,
+    head:   Vec<u8>,        //the first 63 bytes, where boundaries depend on the previous file
+    tail:   Vec<u8>,        //the last 64 bytes, the rollsum window for the next file
+    cuts:   Vec<usize>,     //block ends at or after byte 64
+    hashes: Vec<Vec<u8>>,   //hashes[n] is the hash of cuts[n]..cuts[n+1]
+}
+
+const WINDOW: usize = 64;
+
+fn cut_file(path: &OsString, bits: u32, algo: HashAlgo) -> Result<FileCuts> {
+    let cannot_read = |e| Error::Io(format!("cannot read {:?}", path), e);
+    let chunk_mask = (1 << bits) - 1;
+    let mut f = try!(File::open(path).map_err(&cannot_read));
+    let mut chunker = ::rollsum::Bup::new();
+    let mut hasher = Hasher::new(algo);
+    let mut r = FileCuts {
+        len: 0,
+        head: Vec::new(),
+        tail: Vec::new(),
+        cuts: Vec::new(),
+        hashes: Vec::new(),
+    };
+
+    let mut buf = [0; 4096];
+    loop {
+        let rs = try!(f.read(&mut buf).map_err(&cannot_read));
+        if rs < 1 {
+            break;
+        }
+        let mut sincelastblock = 0;
+        for at in 0..rs {
+            let pos = r.len + at;
+            chunker.roll_byte(buf[at]);
+            if pos < WINDOW - 1 {
+                r.head.push(buf[at]);
+                continue;
+            }
+            if chunker.digest() & chunk_mask == chunk_mask {
+                if r.cuts.len() > 0 {
+                    hasher.input(&buf[sincelastblock..at + 1]);
+                    r.hashes.push(hasher.reset());
+                } else {
+                    hasher = Hasher::new(algo);
+                }
+                sincelastblock = at + 1;
+                r.cuts.push(pos + 1);
+            }
+        }
+        if r.cuts.len() > 0 {
+            hasher.input(&buf[sincelastblock..rs]);
+        }
+
+        r.tail.extend_from_slice(&buf[rs.saturating_sub(WINDOW)..rs]);
+        let drain = r.tail.len().saturating_sub(WINDOW);
+        r.tail.drain(..drain);
+        r.len += rs;
+    }
+    Ok(r)
+}
+
+/// like Chunker, but over host files which are scanned for block boundaries
+/// on multiple threads. emits exactly the same chunks as Chunker would,
+/// and ends with the error of a file that can't be read.
+///
+/// the rollsum only depends on the last 64 bytes, so boundaries inside a file
+/// can be found without knowing the previous files. only the head of each file
+/// and the blocks spanning files are resolved in order.
+pub struct ParallelChunker<I> {
+    files: ::std::vec::IntoIter<(OsString, I)>,
+    jobs:  usize,
+    bits:  u32,
+    per_file: bool,
+
+    ready: VecDeque<Chunk<I>>,
+
+    window: Vec<u8>,
+    hasher: Hasher,
+    current_parts: Vec<ChunkPart<I>>,
+    current_block_len: usize,
+}
+
+impl<I> ParallelChunker<I> where I: Copy {
+    pub fn new(files: Vec<(OsString, I)>, bits: u32, per_file: bool, jobs: usize, algo: HashAlgo) -> ParallelChunker<I> {
+        ParallelChunker {
+            files: files.into_iter(),
+            jobs:  cmp::max(jobs, 1),
+            bits:  bits,
+            per_file: per_file,
+
+            ready: VecDeque::new(),
+
+            window: Vec::new(),
+            hasher: Hasher::new(algo),
+            current_parts: Vec::new(),
+            current_block_len: 0,
+        }
+    }
+
+    /// scan the next batch of files in parallel and merge them in order.
+    /// returns false when there are no more files
+    fn batch(&mut self) -> Result<bool> {
+        let batch: Vec<(OsString, I)> = self.files.by_ref().take(self.jobs * 16).collect();
+        if batch.len() < 1 {
+            return Ok(false);
+        }
+
+        let paths = Arc::new(batch.iter().map(|&(ref p, _)| p.clone()).collect::<Vec<_>>());
+        let next  = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = channel();
+        let workers: Vec<_> = (0..cmp::min(self.jobs, batch.len())).map(|_| {
+            let paths = paths.clone();
+            let next  = next.clone();
+            let tx    = tx.clone();
+            let bits  = self.bits;
+            let algo  = self.hasher.algo();
+            thread::spawn(move || {
+                loop {
+                    let n = next.fetch_add(1, Ordering::SeqCst);
+                    if n >= paths.len() {
+                        break;
+                    }
+                    tx.send((n, cut_file(&paths[n], bits, algo))).unwrap();
+                }
+            })
+        }).collect();
+        drop(tx);
+
+        let mut results: Vec<Option<Result<FileCuts>>> = batch.iter().map(|_| None).collect();
+        for (n, cuts) in rx {
+            results[n] = Some(cuts);
+        }
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        for ((path, i), cuts) in batch.into_iter().zip(results.into_iter()) {
+            try!(self.merge(&path, i, try!(cuts.unwrap())));
+        }
+        Ok(true)
+    }
+
+    fn merge(&mut self, path: &OsString, i: I, fc: FileCuts) -> Result<()> {
+        let cannot_read = |e| Error::Io(format!("cannot read {:?}", path), e);
+        let chunk_mask = (1 << self.bits) - 1;
+
+        // boundaries in the head depend on the end of the previous file
+        if self.per_file {
+            self.window.clear();
+        }
+        let mut chunker = ::rollsum::Bup::new();
+        for b in &self.window {
+            chunker.roll_byte(*b);
+        }
+        let mut cuts = Vec::new();
+        for (at, b) in fc.head.iter().enumerate() {
+            chunker.roll_byte(*b);
+            if chunker.digest() & chunk_mask == chunk_mask {
+                cuts.push((at + 1, None));
+            }
+        }
+        let nhead = cuts.len();
+        for (n, cut) in fc.cuts.iter().enumerate() {
+            cuts.push((*cut, None));
+            if n > 0 {
+                cuts[nhead + n].1 = Some(fc.hashes[n - 1].clone());
+            }
+        }
+
+        if fc.len >= WINDOW {
+            self.window = fc.tail;
+        } else {
+            self.window.extend_from_slice(&fc.head);
+            let drain = self.window.len().saturating_sub(WINDOW);
+            self.window.drain(..drain);
+        }
+
+        let mut f = try!(File::open(path).map_err(&cannot_read));
+        let mut pos = 0;
+        self.current_parts.push(ChunkPart{
+            i: i,
+            file_start: 0,
+            file_end:   0,
+            block_start: self.current_block_len,
+        });
+
+        for (cut, hash) in cuts {
+            let hash = match hash {
+                Some(hash) => {
+                    try!(f.seek(SeekFrom::Current((cut - pos) as i64)).map_err(&cannot_read));
+                    hash
+                },
+                None => {
+                    let mut t = (&mut f).take((cut - pos) as u64);
+                    let mut buf = [0; 4096];
+                    loop {
+                        let rs = try!(t.read(&mut buf).map_err(&cannot_read));
+                        if rs < 1 {
+                            break;
+                        }
+                        self.hasher.input(&buf[..rs]);
+                    }
+                    self.hasher.reset()
+                }
+            };
+            self.current_block_len += cut - pos;
+            self.current_parts.last_mut().as_mut().unwrap().file_end = cut;
+            self.ready.push_back(Chunk{
+                len:  self.current_block_len,
+                hash: hash,
+                parts: ::std::mem::replace(&mut self.current_parts, Vec::new()),
+            });
+            self.current_parts.push(ChunkPart{
+                i: i,
+                file_start: cut,
+                file_end:   0,
+                block_start: 0,
+            });
+            self.current_block_len = 0;
+            pos = cut;
+        }
+
+        let mut buf = [0; 4096];
+        let mut t = f.take((fc.len - pos) as u64);
+        loop {
+            let rs = try!(t.read(&mut buf).map_err(&cannot_read));
+            if rs < 1 {
+                break;
+            }
+            self.hasher.input(&buf[..rs]);
+        }
+        self.current_block_len += fc.len - pos;
+        self.current_parts.last_mut().as_mut().unwrap().file_end = fc.len;
+
+        if self.per_file {
+            if file_ended_on_boundary(&self.current_parts, self.current_block_len) {
+                self.current_parts.clear();
+                return Ok(());
             }
+            self.ready.push_back(Chunk{
+                len:  ::std::mem::replace(&mut self.current_block_len, 0),
+                hash: self.hasher.reset(),
+                parts: ::std::mem::replace(&mut self.current_parts, Vec::new()),
+            });
         }
+        Ok(())
     }
 }
 
+impl<I> Iterator for ParallelChunker<I> where I: Copy {
+    type Item = Result<Chunk<I>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(c) = self.ready.pop_front() {
+                return Some(Ok(c));
+            }
+            match self.batch() {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(e) => {
+                    self.files = Vec::new().into_iter();
+                    self.ready.clear();
+                    self.current_parts.clear();
+                    return Some(Err(e));
+                },
+            }
+        }
+        //rest
+        if self.current_parts.len() > 0 {
+            let hash = self.hasher.reset();
+            return Some(Ok(Chunk{
+                len:  ::std::mem::replace(&mut self.current_block_len, 0),
+                hash: hash,
+                parts: ::std::mem::replace(&mut self.current_parts, Vec::new()),
+            }));
+        }
+        None
+    }
+}
+
+
+#[cfg(test)]
+use std::io::Write;
+
+#[test]
+fn parallel_matches_sequential() {
+    let mut seed: u32 = 2463534242;
+    let mut random = |n: usize| -> Vec<u8> {
+        (0..n).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        }).collect()
+    };
+
+    let contents = vec![
+        random(100000),
+        Vec::new(),
+        random(10),
+        random(63),
+        random(64),
+        vec![0; 5000],
+        random(3000),
+        random(1),
+        random(70000),
+    ];
+    let files: Vec<::tempfile::NamedTempFile> = contents.iter().map(|c| {
+        let mut f = ::tempfile::NamedTempFile::new().unwrap();
+        f.write_all(c).unwrap();
+        f
+    }).collect();
+
+    for &(bits, per_file) in &[(4, false), (9, false), (4, true), (9, true)] {
+        let it = files.iter().enumerate().map(|(i, f)| (File::open(f.path()).unwrap(), i));
+        let sequential: Vec<Chunk<usize>> = Chunker::new(Box::new(it), ::rollsum::Bup::new(), bits, per_file, HashAlgo::Blake3)
+            .collect::<Result<_>>().unwrap();
+
+        if per_file {
+            assert!(sequential.iter().all(|c| c.parts.len() == 1));
+        }
+
+        for jobs in 1..5 {
+            let paths = files.iter().enumerate().map(|(i, f)| (f.path().as_os_str().to_owned(), i)).collect();
+            let parallel: Vec<Chunk<usize>> = ParallelChunker::new(paths, bits, per_file, jobs, HashAlgo::Blake3)
+                .collect::<Result<_>>().unwrap();
+
+            assert_eq!(sequential.len(), parallel.len());
+            for (a, b) in sequential.iter().zip(parallel.iter()) {
+                assert_eq!(a.len, b.len);
+                assert_eq!(a.hash, b.hash);
+                assert_eq!(a.parts.len(), b.parts.len());
+                for (pa, pb) in a.parts.iter().zip(b.parts.iter()) {
+                    assert_eq!((pa.i, pa.file_start, pa.file_end, pa.block_start),
+                               (pb.i, pb.file_start, pb.file_end, pb.block_start));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use error::{Error, Result};
+use hash;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use sha2::{Sha256, Digest};
+
+/// convergent encryption of blocks with a store secret.
+///
+/// a block is encrypted with a key derived from its id and the secret,
+/// so equal content still dedups within the store, but nobody without the secret
+/// can tell which content a block holds, or confirm a guess of it.
+/// blocks are named by a keyed hash of their id, so names don't leak plaintext hashes either
+#[derive(Clone)]
+pub struct Crypt {
+    secret: Vec<u8>,
+}
+
+impl Crypt {
+    pub fn new(secret: Vec<u8>) -> Crypt {
+        Crypt {
+            secret: secret,
+        }
+    }
+
+    fn derive(&self, purpose: &[u8], id: &[u8]) -> Vec<u8> {
+        let mut h = Sha256::default();
+        h.input(&self.secret);
+        h.input(purpose);
+        h.input(&hash::normalize(id));
+        h.result().as_slice().to_vec()
+    }
+
+    /// the name a block is stored under
+    pub fn name(&self, id: &[u8]) -> Vec<u8> {
+        self.derive(b"archon block name", id)
+    }
+
+    fn key(&self, id: &[u8]) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &self.derive(b"archon block key", id)).unwrap())
+    }
+
+    pub fn seal(&self, id: &[u8], mut content: Vec<u8>) -> Vec<u8> {
+        let nonce = Nonce::assume_unique_for_key([0; NONCE_LEN]); //every key only ever seals the same content
+        self.key(id).seal_in_place_append_tag(nonce, Aad::from(self.name(id)), &mut content).unwrap();
+        content
+    }
+
+    pub fn open(&self, id: &[u8], mut content: Vec<u8>) -> Result<Vec<u8>> {
+        let nonce = Nonce::assume_unique_for_key([0; NONCE_LEN]);
+        let len = try!(self.key(id).open_in_place(nonce, Aad::from(self.name(id)), &mut content)
+                       .map_err(|_| Error::CorruptBlock(id.to_vec()))).len();
+        content.truncate(len);
+        Ok(content)
+    }
+}
+
+#[test]
+fn sealed_blocks_open_only_with_their_id() {
+    let crypt = Crypt::new(vec![7; 32]);
+    let a = hash::digest(hash::HashAlgo::Sha256, b"a");
+    let b = hash::digest(hash::HashAlgo::Sha256, b"b");
+
+    let sealed = crypt.seal(&a, b"a".to_vec());
+    assert!(sealed != b"a".to_vec());
+    assert_eq!(crypt.seal(&a, b"a".to_vec()), sealed);
+    assert_eq!(crypt.open(&a, sealed.clone()).unwrap(), b"a".to_vec());
+    assert!(crypt.open(&b, sealed.clone()).is_err());
+    assert!(Crypt::new(vec![8; 32]).open(&a, sealed).is_err());
+    assert!(crypt.name(&a) != Crypt::new(vec![8; 32]).name(&a));
+}
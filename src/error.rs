@@ -0,0 +1,71 @@
+use hex::ToHex;
+use libc::{c_int, EIO, ENOENT, EINVAL, EACCES};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// everything that can go wrong in archon
+#[derive(Debug)]
+pub enum Error {
+    MissingBlock(Vec<u8>),          //referenced but not in the store
+    CorruptBlock(Vec<u8>),          //content doesn't match its id, or can't be decrypted
+    CorruptIndex(String),           //blocks that don't deserialize to an index
+    BadIndexVersion(u16),
+    Io(String, io::Error),          //what was being done, and the error
+    NotInitialized(PathBuf),        //no store at this path
+    NotFound(String),               //a name or path that isn't in the store or index
+    Signature(String),              //an index that isn't signed as required
+    Invalid(String),                //bad input, like a malformed key or ELF file
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl Error {
+    /// what a FUSE operation replies with
+    pub fn errno(&self) -> c_int {
+        match *self {
+            Error::NotFound(_)  => ENOENT,
+            Error::Signature(_) => EACCES,
+            Error::Invalid(_)   => EINVAL,
+            Error::Io(_, ref e) => e.raw_os_error().unwrap_or(EIO),
+            _ => EIO,
+        }
+    }
+
+    /// what the cli exits with
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::Invalid(_)         => 1,
+            Error::Io(_, _)           => 2,
+            Error::NotInitialized(_)  => 3,
+            Error::NotFound(_)        => 4,
+            Error::MissingBlock(_)    => 5,
+            Error::CorruptBlock(_)    => 6,
+            Error::CorruptIndex(_)    => 6,
+            Error::BadIndexVersion(_) => 7,
+            Error::Signature(_)       => 8,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::MissingBlock(ref h)   => write!(f, "block {} not found", h.to_hex()),
+            Error::CorruptBlock(ref h)   => write!(f, "block {} does not match its hash", h.to_hex()),
+            Error::CorruptIndex(ref e)   => write!(f, "corrupt index: {}", e),
+            Error::BadIndexVersion(v)    => write!(f, "unsupported index version {}", v),
+            Error::Io(ref what, ref e)   => write!(f, "{}: {}", what, e),
+            Error::NotInitialized(ref p) => write!(f, "no store at {}", p.display()),
+            Error::NotFound(ref what)    => write!(f, "{} not found", what),
+            Error::Signature(ref e)      => write!(f, "{}", e),
+            Error::Invalid(ref e)        => write!(f, "{}", e),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn description(&self) -> &str {
+        "archon error"
+    }
+}
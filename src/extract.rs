@@ -0,0 +1,44 @@
+use blockstore::BlockStore;
+use error::{Error, Result};
+use index::Index;
+use readchain::Take;
+use std::fs::{File, create_dir_all};
+use std::io::{self, Write, Seek, SeekFrom};
+use std::path::Path;
+
+impl Index {
+    /// write all files of the index below target. holes are skipped, so sparse files stay sparse
+    pub fn extract(&self, blockstore: &BlockStore, target: &Path) -> Result<()> {
+        let mut paths: Vec<(String, u64)> = self.paths().into_iter().collect();
+        paths.sort();
+
+        try!(create_dir_all(target).map_err(|e| Error::Io(format!("cannot create {}", target.display()), e)));
+        for (path, inode) in paths {
+            let entry = &self.i[inode as usize];
+            let p = target.join(&path[1..]);
+            let cannot_write = |e| Error::Io(format!("cannot write {}", p.display()), e);
+            if entry.kind == 1 {
+                try!(create_dir_all(&p).map_err(&cannot_write));
+                continue;
+            }
+
+            let mut f = try!(File::create(&p).map_err(&cannot_write));
+            if let Some(ref inline) = entry.inline {
+                try!(f.write_all(inline).map_err(&cannot_write));
+            } else if let Some(ref content) = entry.content {
+                for c in content {
+                    if c.is_hole() {
+                        try!(f.seek(SeekFrom::Current(c.l as i64)).map_err(&cannot_write));
+                        continue;
+                    }
+                    let mut re = try!(blockstore.read(&c.h));
+                    try!(re.seek(SeekFrom::Current(c.o as i64)).map_err(|_| Error::CorruptBlock(c.h.clone())));
+                    try!(io::copy(&mut Take::limit(re, c.l as usize), &mut f).map_err(&cannot_write));
+                }
+            }
+            // a trailing hole has nothing written after it
+            try!(f.set_len(entry.size).map_err(&cannot_write));
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,28 @@
-use blockstore::{BlockStore};
+use blockstore::{BlockStore, BlockReader};
+use cache::Cache;
+use error::Error;
 use fuse::*;
-use index::{Index, Inode};
-use libc::ENOENT;
+use hash;
+use hex::{ToHex, FromHex};
+use index::{Index, Inode, ContentBlockEntry};
+use libc::{ENOENT, EIO, EBADF, EPERM, EROFS, O_ACCMODE, O_RDONLY, O_TRUNC};
+use nix::unistd::{fork, ForkResult, setsid, pipe, read, write, close, dup2, getuid, getgid};
+use overlay::Overlay;
 use readchain::{Take,Chain};
 use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Cursor};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use store::Store;
+use trace::{Access, Recorder};
 use time::Timespec;
 use std::boxed::Box;
 
@@ -14,11 +30,24 @@ const TTL: Timespec = Timespec { sec: 1, nsec: 0 };                 // 1 second
 
 const CREATE_TIME: Timespec = Timespec { sec: 1381237736, nsec: 0 };    // 2013-10-08 08:56
 
-fn entry_to_file_attr(entry: &Inode) -> FileAttr{
+/// bytes that take up space, which excludes holes
+fn allocated(entry: &Inode) -> u64 {
+    if !entry.host_path.is_empty() {
+        return entry.size;
+    }
+    match entry.content {
+        Some(ref content) if entry.inline.is_none() => {
+            content.iter().filter(|c| !c.is_hole()).fold(0, |acc, c| acc + c.l)
+        },
+        _ => entry.size,
+    }
+}
+
+fn entry_to_file_attr(ino: u64, entry: &Inode) -> FileAttr{
     FileAttr {
-        ino:    entry.inode + 1,
+        ino:    ino,
         size:   entry.size,
-        blocks: entry.size * 512,
+        blocks: (allocated(entry) + 511) / 512,
         atime:  CREATE_TIME,
         mtime:  CREATE_TIME,
         ctime:  CREATE_TIME,
@@ -32,135 +61,1142 @@ fn entry_to_file_attr(entry: &Inode) -> FileAttr{
             Some(ref d) => d.len() + 1,
             _ => 1,
         } as u32,
-        uid: 1000,
-        gid: 1000,
+        uid: 0, //the index has no owners, see Fuse::own
+        gid: 0,
         rdev: 0,
         flags: 0,
     }
 }
 
+/// a directory that isn't loaded from an index, like the root of a store mount
+fn dir_attr(ino: u64, perm: u16) -> FileAttr {
+    FileAttr {
+        ino:    ino,
+        size:   0,
+        blocks: 0,
+        atime:  CREATE_TIME,
+        mtime:  CREATE_TIME,
+        ctime:  CREATE_TIME,
+        crtime: CREATE_TIME,
+        kind:   FileType::Directory,
+        perm:   perm,
+        nlink:  2,
+        uid: 0, //the index has no owners, see Fuse::own
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+// every index of a mount has a slot, and inode numbers carry it in their upper bits
+const SLOT_SHIFT: u64 = 40;
 
-pub struct Fuse<'a> {
-    index:      &'a Index,
-    blockstore: &'a BlockStore,
-    open_files:  HashMap<u64, Box<Read + 'a>>,
+fn to_ino(slot: usize, inode: u64) -> u64 {
+    ((slot as u64) << SLOT_SHIFT) + inode + 1
 }
 
-impl<'a> Fuse<'a> {
-    pub fn new(index: &'a Index, blockstore: &'a BlockStore) -> Fuse<'a> {
-        Fuse{
-            index: index,
-            blockstore: blockstore,
-            open_files: HashMap::new(),
+fn from_ino(ino: u64) -> (usize, u64) {
+    ((ino >> SLOT_SHIFT) as usize, (ino & ((1 << SLOT_SHIFT) - 1)) - 1)
+}
+
+// the directories of slot 0 of a store mount
+const STORE_ROOT: u64 = 0;
+const BY_HASH:    u64 = 1;
+
+/// an open file, served from its blocks or from the upper layer of a writable mount
+enum Handle {
+    Blocks(Blocks),
+    Inline(Vec<u8>),
+    Upper(File),
+}
+
+/// the content of an open file in blocks
+struct Blocks {
+    content: Vec<ContentBlockEntry>,
+    starts:  Vec<u64>, //where each entry starts, followed by the end
+    next:    AtomicUsize, //where the last read ended
+}
+
+impl Blocks {
+    fn new(content: Vec<ContentBlockEntry>) -> Blocks {
+        let mut starts = Vec::with_capacity(content.len() + 1);
+        let mut at = 0;
+        starts.push(at);
+        for c in &content {
+            at += c.l;
+            starts.push(at);
+        }
+        Blocks {
+            content: content,
+            starts:  starts,
+            next:    AtomicUsize::new(0),
         }
     }
+
+    /// the entry holding offset. past the end this is the number of entries
+    fn entry(&self, offset: u64) -> usize {
+        match self.starts.binary_search(&offset) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        }
+    }
+
+    /// size bytes at offset, with blocks from the cache
+    fn read(&self, cache: &Cache, blockstore: &BlockStore, offset: u64, size: usize) -> Result<Vec<u8>, Error> {
+        let mut r = Vec::with_capacity(size);
+        let mut i = self.entry(offset);
+        while i < self.content.len() && r.len() < size {
+            let c = &self.content[i];
+            let at = offset + r.len() as u64 - self.starts[i];
+            let n = ::std::cmp::min(c.l - at, (size - r.len()) as u64) as usize;
+            if c.is_hole() {
+                r.resize(r.len() + n, 0);
+            } else {
+                let block = try!(cache.block(blockstore, &c.h));
+                let from = (c.o + at) as usize;
+                if from + n > block.len() {
+                    return Err(Error::CorruptBlock(c.h.clone()));
+                }
+                r.extend_from_slice(&block[from..from + n]);
+            }
+            i += 1;
+        }
+        Ok(r)
+    }
+
+    /// ids of the blocks of count entries from offset
+    fn ids(&self, offset: u64, count: usize) -> Vec<&Vec<u8>> {
+        self.content.iter().skip(self.entry(offset)).take(count).filter(|c| !c.is_hole()).map(|c| &c.h).collect()
+    }
 }
 
-impl<'a>  Filesystem for Fuse<'a> {
-    fn lookup (&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+/// a file opened by fuse. only read by the threads serving reads
+struct Open {
+    handle: Handle,
+    path:   Option<String>, //from the root of the mount, when reads are traced
+}
+
+impl Open {
+    fn read(&self, cache: &Cache, blockstore: &BlockStore, offset: u64, size: usize, readahead: usize) -> Result<Vec<u8>, Error> {
+        match self.handle {
+            Handle::Blocks(ref blocks) => {
+                let r = blocks.read(cache, blockstore, offset, size);
+                // a read where the last one ended is sequential, so the next blocks are needed soon
+                let last = blocks.next.swap((offset + size as u64) as usize, Ordering::Relaxed);
+                if offset == last as u64 && offset > 0 && readahead > 0 {
+                    cache.prefetch(blockstore, blocks.ids(offset + size as u64, readahead));
+                }
+                r
+            },
+            Handle::Inline(ref inline) => {
+                let from = ::std::cmp::min(offset as usize, inline.len());
+                let to   = ::std::cmp::min(from + size, inline.len());
+                Ok(inline[from..to].to_vec())
+            },
+            Handle::Upper(ref f) => {
+                let mut buf = vec![0; size];
+                f.read_at(&mut buf, offset).map(|n| {
+                    buf.truncate(n);
+                    buf
+                }).map_err(|e| Error::Io("cannot read from the upper layer".to_owned(), e))
+            },
+        }
+    }
+}
+
+/// threads running jobs, such as reads and their replies
+struct Pool {
+    tx: Sender<Box<FnOnce() + Send>>,
+}
 
-        let mb = self.index.i.get((parent - 1) as usize)
-            .and_then(|entry| entry.dir.as_ref())
-            .and_then(|d| d.get(&name.to_string_lossy().into_owned()))
-            .and_then(|e| self.index.i.get(e.i as usize));
+impl Pool {
+    fn new(threads: usize) -> Pool {
+        let (tx, rx) = channel::<Box<FnOnce() + Send>>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..threads {
+            let rx = rx.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = rx.lock().unwrap().recv();
+                    match job {
+                        Err(_) => return, //the mount is gone
+                        Ok(job) => job(),
+                    }
+                }
+            });
+        }
+        Pool {
+            tx: tx,
+        }
+    }
+}
+
+/// an index of a mount, loaded when it is first looked into.
+/// each is locked on its own, so the worker threads can load it and its directories
+struct Image {
+    slot:   usize,
+    name:   String, //name of the index, or by-hash/ and its root hash
+    pubkey: Option<String>, //key the index must be signed by
+    index:  RwLock<Option<Index>>,
+}
+
+impl Image {
+    fn new(slot: usize, name: String, pubkey: Option<String>, index: Option<Index>) -> Image {
+        Image {
+            slot:   slot,
+            name:   name,
+            pubkey: pubkey,
+            index:  RwLock::new(index),
+        }
+    }
 
-        match mb {
-            None => reply.error(ENOENT),
-            Some(entry) => {
-                let fa = &entry_to_file_attr(entry);
-                reply.entry(&TTL, fa, 0)
+    /// the index, loaded with the directory of inode if dir is set
+    fn loaded<'a>(&'a self, store: &Store, inode: u64, dir: bool) -> Result<RwLockReadGuard<'a, Option<Index>>, Error> {
+        {
+            let index = self.index.read().unwrap();
+            let done = match *index {
+                None => false,
+                Some(ref index) => !dir || index.i.get(inode as usize).map(|i| i.dir.is_some() || i.tree.is_none()).unwrap_or(true),
+            };
+            if done {
+                return Ok(index);
             }
         }
+        {
+            let mut index = self.index.write().unwrap();
+            if index.is_none() {
+                println!("loading index {:?}", self.name);
+                *index = Some(if self.name.starts_with("by-hash/") {
+                    let root = try!(Vec::<u8>::from_hex(&self.name[8..]).map_err(|_| Error::NotFound(self.name.clone())));
+                    try!(store.load_hash(&root, true))
+                } else {
+                    try!(store.load(&self.name, self.pubkey.as_ref().map(|k| &k[..]), true))
+                });
+            }
+            if dir {
+                try!(index.as_mut().unwrap().load_dir(&store.blockstore, inode));
+            }
+        }
+        Ok(self.index.read().unwrap())
     }
 
-    fn getattr (&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        println!("getattr {:?}", ino);
+    /// the inode of an ino of this image, or not found if there is no such inode
+    fn inode(&self, index: &Index, ino: u64) -> Result<u64, Error> {
+        let (_, inode) = from_ino(ino);
+        match index.i.get(inode as usize) {
+            None => Err(Error::NotFound(format!("inode {}", ino))),
+            Some(_) => Ok(inode),
+        }
+    }
+
+    /// attributes of an ino, before the mount owns them
+    fn attr(&self, store: &Store, ino: u64) -> Result<FileAttr, Error> {
+        let (_, inode) = from_ino(ino);
+        // the root of an index is the same for every index, so it doesn't need loading
+        if inode == 0 && self.index.read().unwrap().is_none() {
+            return Ok(dir_attr(ino, 0o775));
+        }
+        let index = try!(self.loaded(store, inode, false));
+        let index = index.as_ref().unwrap();
+        let inode = try!(self.inode(index, ino));
+        Ok(entry_to_file_attr(ino, &index.i[inode as usize]))
+    }
+
+    /// the entries of a directory, as ino, kind and name
+    fn entries(&self, store: &Store, ino: u64) -> Result<Vec<(u64, FileType, String)>, Error> {
+        let index = try!(self.loaded(store, from_ino(ino).1, true));
+        let index = index.as_ref().unwrap();
+        let inode = try!(self.inode(index, ino));
+        Ok(match index.i[inode as usize].dir {
+            None => Vec::new(),
+            Some(ref dir) => dir.iter().map(|(name, e)| (to_ino(self.slot, e.i), match e.k {
+                1 => FileType::Directory,
+                _ => FileType::RegularFile,
+            }, name.clone())).collect(),
+        })
+    }
+
+    /// the path of an inode from the root of the mount
+    fn path(&self, index: &Index, inode: u64) -> String {
+        let prefix = if self.name.is_empty() { String::new() } else { format!("/{}", self.name) };
+        prefix + &index.path_of(inode)
+    }
+
+    /// the ino of a name in a directory
+    fn find(&self, store: &Store, parent: u64, name: &str) -> Result<u64, Error> {
+        let index = try!(self.loaded(store, from_ino(parent).1, true));
+        let index = index.as_ref().unwrap();
+        let inode = try!(self.inode(index, parent));
+        match index.i[inode as usize].dir.as_ref().and_then(|d| d.get(name)) {
+            None => Err(Error::NotFound(name.to_owned())),
+            Some(e) => Ok(to_ino(self.slot, e.i)),
+        }
+    }
+}
+
+/// how a mount shows the owner and permissions of its files
+#[derive(Clone, Copy)]
+struct Shown {
+    uid:       u32,
+    gid:       u32,
+    root_perm: Option<u16>,
+    read_only: bool,
+}
+
+impl Shown {
+    fn own(&self, mut attr: FileAttr) -> FileAttr {
+        attr.uid = self.uid;
+        attr.gid = self.gid;
+        if let Some(perm) = self.root_perm {
+            if attr.ino == to_ino(0, 0) {
+                attr.perm = perm;
+            }
+        }
+        if self.read_only {
+            attr.perm &= !0o222;
+        }
+        attr
+    }
+}
+
+/// where the indices of a mount come from
+enum Source {
+    Single, //one index at the root, in slot 0
+    Store {
+        by_hash: bool, //also serve any index by its root hash below by-hash/
+        pubkey:  Option<String>,
+    },
+}
+
+/// directories of an index v2 are loaded when they are first looked at.
+/// with an overlay, inodes whose host_path is set live in its upper layer.
+///
+/// the overlay belongs to the fuse session thread. reads, and lookups and opens
+/// that may have to load an index or a directory, run on the worker threads
+pub struct Fuse {
+    images:     Vec<Arc<Image>>, //by slot
+    slots:      HashMap<String, usize>,
+    source:     Source,
+    store:      Arc<Store>,
+    open_files: Arc<Mutex<HashMap<u64, Arc<Open>>>>,
+    overlay:    Option<Overlay>,
+    cache:      Arc<Cache>,
+    trace:      Option<Arc<Mutex<Recorder>>>, //where to record reads
+    workers:    Option<Pool>, //None reads on the session thread
+    pub readahead: usize, //blocks to prefetch on sequential reads
+    pub uid:       u32, //owner of every file, the mounting user by default
+    pub gid:       u32,
+    pub root_perm: Option<u16>, //permissions of the mountpoint, instead of those of the root directory
+    pub read_only: bool, //show no write permissions
+}
+
+impl Fuse {
+    pub fn new(index: Index, store: Arc<Store>) -> Fuse {
+        Fuse::with_source(Some(index), Source::Single, store)
+    }
+
+    fn with_source(index: Option<Index>, source: Source, store: Arc<Store>) -> Fuse {
+        Fuse{
+            images: vec![Arc::new(Image::new(0, String::new(), None, index))],
+            slots: HashMap::new(),
+            source: source,
+            store: store,
+            open_files: Arc::new(Mutex::new(HashMap::new())),
+            overlay: None,
+            cache: Arc::new(Cache::default()),
+            trace: None,
+            workers: None,
+            readahead: 0,
+            uid: getuid(),
+            gid: getgid(),
+            root_perm: None,
+            read_only: false,
+        }
+    }
+
+    /// a mount that records changes in overlay, showing the changes it already has
+    pub fn writable(mut index: Index, store: Arc<Store>, overlay: Overlay) -> Result<Fuse, Error> {
+        try!(overlay.apply(&mut index, &store.blockstore));
+        let mut fs = Fuse::new(index, store);
+        fs.overlay = Some(overlay);
+        Ok(fs)
+    }
+
+    /// a mount whose root lists every named index of store, sharing its blocks.
+    /// with a public key, only indices signed by it can be looked into
+    pub fn store(store: Arc<Store>, by_hash: bool, pubkey: Option<String>) -> Fuse {
+        Fuse::with_source(None, Source::Store {
+            by_hash: by_hash,
+            pubkey:  pubkey,
+        }, store)
+    }
+
+    pub fn set_cache(&mut self, cache: Cache) {
+        self.cache = Arc::new(cache);
+    }
+
+    /// record every read to a trace
+    pub fn set_trace(&mut self, trace: Recorder) {
+        self.trace = Some(Arc::new(Mutex::new(trace)));
+    }
+
+    /// serve reads on this many threads, so a slow block doesn't hold up everything else.
+    /// with none, reads are served one after another with every other request
+    pub fn set_workers(&mut self, threads: usize) {
+        self.workers = match threads {
+            0 => None,
+            n => Some(Pool::new(n)),
+        };
+    }
+
+    fn is_virtual(&self, slot: usize) -> bool {
+        match self.source {
+            Source::Store{..} => slot == 0,
+            Source::Single    => false,
+        }
+    }
+
+    /// the slot of an index of a store mount, which is loaded later
+    fn slot(&mut self, name: String) -> usize {
+        if let Some(slot) = self.slots.get(&name) {
+            return *slot;
+        }
+        let slot = self.images.len();
+        let pubkey = match self.source {
+            Source::Store{ref pubkey, ..} if !name.starts_with("by-hash/") => pubkey.clone(),
+            _ => None,
+        };
+        self.slots.insert(name.clone(), slot);
+        self.images.push(Arc::new(Image::new(slot, name, pubkey, None)));
+        slot
+    }
+
+    /// the image of an ino, which may not be loaded yet, and its inode
+    fn image(&self, ino: u64) -> Result<(Arc<Image>, u64), Error> {
+        let (slot, inode) = from_ino(ino);
+        if slot >= self.images.len() || self.is_virtual(slot) {
+            return Err(Error::NotFound(format!("inode {}", ino)));
+        }
+        Ok((self.images[slot].clone(), inode))
+    }
+
+    /// load the index of a slot if it isn't yet, and return the inode of an ino
+    fn load(&self, ino: u64) -> Result<(usize, u64), Error> {
+        let (image, _) = try!(self.image(ino));
+        let index = try!(image.loaded(&self.store, 0, false));
+        Ok((image.slot, try!(image.inode(index.as_ref().unwrap(), ino))))
+    }
+
+    /// the index of a loaded slot, locked for changes
+    fn index<'a>(&'a self, slot: usize) -> RwLockWriteGuard<'a, Option<Index>> {
+        self.images[slot].index.write().unwrap()
+    }
+
+    fn add_handle(&mut self, ino: u64, handle: Handle) -> u64 {
+        let path = match self.trace {
+            Some(_) => Some(self.path(ino)),
+            None => None,
+        };
+        add_open(&self.open_files, ino, handle, path)
+    }
+
+    /// run a job on the worker threads, or right away without them
+    fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        match self.workers {
+            None => job(),
+            Some(ref pool) => pool.tx.send(Box::new(job)).unwrap(),
+        }
+    }
+
+    /// how this mount shows its files
+    fn shown(&self) -> Shown {
+        Shown {
+            uid:       self.uid,
+            gid:       self.gid,
+            root_perm: self.root_perm,
+            read_only: self.read_only,
+        }
+    }
+
+    /// attributes as this mount shows them
+    fn own(&self, attr: FileAttr) -> FileAttr {
+        self.shown().own(attr)
+    }
+
+    fn attr(&self, ino: u64) -> Result<FileAttr, Error> {
+        let (slot, inode) = from_ino(ino);
+        if self.is_virtual(slot) && inode <= BY_HASH {
+            return Ok(self.own(dir_attr(ino, 0o555)));
+        }
+        let (image, _) = try!(self.image(ino));
+        image.attr(&self.store, ino).map(|attr| self.own(attr))
+    }
+
+    /// the entries of a directory, as ino, kind and name
+    fn entries(&mut self, ino: u64) -> Result<Vec<(u64, FileType, String)>, Error> {
+        let (slot, inode) = from_ino(ino);
+        if self.is_virtual(slot) {
+            let store = self.store.clone();
+            let by_hash = match self.source {
+                Source::Store{by_hash, ..} => by_hash,
+                Source::Single => unreachable!(),
+            };
+            let mut r = Vec::new();
+            if inode == STORE_ROOT {
+                if by_hash {
+                    r.push((to_ino(0, BY_HASH), FileType::Directory, "by-hash".to_owned()));
+                }
+                for name in try!(store.list()) {
+                    let slot = self.slot(name.clone());
+                    r.push((to_ino(slot, 0), FileType::Directory, name));
+                }
+            } else {
+                for name in try!(store.list()) {
+                    if let Ok(root) = store.tree_root(&name) {
+                        let slot = self.slot(format!("by-hash/{}", root.to_hex()));
+                        r.push((to_ino(slot, 0), FileType::Directory, root.to_hex()));
+                    }
+                }
+            }
+            return Ok(r);
+        }
+
+        let (image, _) = try!(self.image(ino));
+        image.entries(&self.store, ino)
+    }
+
+    /// the ino of a name in a directory
+    fn find(&mut self, parent: u64, name: &str) -> Result<u64, Error> {
+        let (slot, inode) = from_ino(parent);
+        if self.is_virtual(slot) {
+            let store = self.store.clone();
+            let by_hash = match self.source {
+                Source::Store{by_hash, ..} => by_hash,
+                Source::Single => unreachable!(),
+            };
+            if inode == STORE_ROOT && by_hash && name == "by-hash" {
+                return Ok(to_ino(0, BY_HASH));
+            }
+            if inode == STORE_ROOT && try!(store.list()).iter().any(|n| n == name) {
+                return Ok(to_ino(self.slot(name.to_owned()), 0));
+            }
+            if inode == BY_HASH {
+                if let Ok(root) = Vec::<u8>::from_hex(name) {
+                    let root = hash::normalize(&root);
+                    let name = format!("by-hash/{}", root.to_hex());
+                    if !self.slots.contains_key(&name) {
+                        // only roots of an index get a slot, so looking up any other block doesn't grow the mount
+                        let index = try!(store.load_hash(&root, true).map_err(|_| Error::NotFound(name.clone())));
+                        let slot = self.slot(name.clone());
+                        *self.index(slot) = Some(index);
+                    }
+                    return Ok(to_ino(self.slot(name), 0));
+                }
+            }
+            return Err(Error::NotFound(name.to_owned()));
+        }
+
+        let (image, _) = try!(self.image(parent));
+        image.find(&self.store, parent, name)
+    }
+
+    /// the path of a loaded ino from the root of the mount
+    fn path(&self, ino: u64) -> String {
+        let (slot, inode) = from_ino(ino);
+        let image = &self.images[slot];
+        let index = image.index.read().unwrap();
+        image.path(index.as_ref().unwrap(), inode)
+    }
 
-        match self.index.i.get((ino - 1) as usize) {
-            None => reply.error(ENOENT),
-            Some(entry) => {
-                reply.attr(&TTL, &entry_to_file_attr(entry));
+    /// the ino of a path from the root of the mount
+    fn resolve(&mut self, path: &str) -> Result<u64, Error> {
+        let mut ino = to_ino(0, 0);
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            ino = try!(self.find(ino, name));
+        }
+        Ok(ino)
+    }
+
+    /// prefetch the blocks of accesses, such as a trace of an earlier mount.
+    /// returns how many of them were found
+    pub fn warm(&mut self, accesses: &[Access]) -> usize {
+        let mut found = 0;
+        for a in accesses {
+            let content = match self.resolve(&a.path).and_then(|ino| self.load(ino)) {
+                Ok((slot, inode)) => self.index(slot).as_ref().unwrap().i[inode as usize].content.clone(),
+                Err(_) => None,
+            };
+            if let Some(content) = content {
+                let blocks = Blocks::new(content);
+                let count = blocks.entry(a.offset + a.len) - blocks.entry(a.offset) + 1;
+                self.cache.prefetch(&self.store.blockstore, blocks.ids(a.offset, count));
+                found += 1;
             }
         }
+        found
+    }
+
+    /// the index and inode of a writable mount. everything else is read only
+    fn writable_inode(&self, ino: u64) -> Result<u64, Error> {
+        if self.overlay.is_none() {
+            return Err(read_only());
+        }
+        self.load(ino).map(|(_, inode)| inode)
+    }
+}
+
+/// a handle to the content of an inode
+fn handle_of(entry: &Inode) -> Result<Handle, Error> {
+    if !entry.host_path.is_empty() {
+        upper_file(&entry.host_path).map(Handle::Upper)
+    } else if let Some(ref inline) = entry.inline {
+        Ok(Handle::Inline(inline.clone()))
+    } else {
+        Ok(Handle::Blocks(Blocks::new(entry.content.clone().unwrap_or_default())))
+    }
+}
+
+/// keep an open file, returning its file handle
+fn add_open(open_files: &Mutex<HashMap<u64, Arc<Open>>>, ino: u64, handle: Handle, path: Option<String>) -> u64 {
+    let mut open_files = open_files.lock().unwrap();
+    let mut fh = ino;
+    while open_files.contains_key(&fh) {
+        fh += 1;
+    }
+    open_files.insert(fh, Arc::new(Open {
+        handle: handle,
+        path:   path,
+    }));
+    fh
+}
+
+fn read_only() -> Error {
+    Error::Io("mount is read only".to_owned(), io::Error::from_raw_os_error(EROFS))
+}
+
+fn upper_file(path: &::std::ffi::OsString) -> Result<File, Error> {
+    OpenOptions::new().read(true).write(true).open(path).map_err(|e| Error::Io(format!("cannot open {:?}", path), e))
+}
+
+/// make a change to a writable mount, or reply with its error and return
+macro_rules! change {
+    ($fs: ident, $reply: ident, $what: expr, |$overlay: ident, $index: ident| $change: expr) => {{
+        let r = match $fs.overlay {
+            None => Err(read_only()),
+            Some(ref mut $overlay) => {
+                let mut guard = $fs.images[0].index.write().unwrap();
+                let $index = guard.as_mut().unwrap();
+                $change
+            },
+        };
+        match r {
+            Ok(r) => r,
+            Err(e) => {
+                println!("{} : {}", $what, e);
+                $reply.error(e.errno());
+                return;
+            },
+        }
+    }}
+}
+
+impl Filesystem for Fuse {
+    fn lookup (&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = name.to_string_lossy().into_owned();
+        let (image, store, shown) = match self.image(parent) {
+            // the directories of a store mount are listed right away
+            Err(_) => return reply_entry(parent, self.find(parent, &name).and_then(|ino| self.attr(ino)), reply),
+            Ok((image, _)) => (image, self.store.clone(), self.shown()),
+        };
+        self.spawn(move || {
+            let attr = image.find(&store, parent, &name).and_then(|ino| image.attr(&store, ino)).map(|attr| shown.own(attr));
+            reply_entry(parent, attr, reply)
+        });
     }
 
+    fn getattr (&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        println!("getattr {:?}", ino);
+
+        let (image, store, shown) = match self.image(ino) {
+            Err(_) => return reply_attr(self.attr(ino), reply),
+            Ok((image, _)) => (image, self.store.clone(), self.shown()),
+        };
+        self.spawn(move || reply_attr(image.attr(&store, ino).map(|attr| shown.own(attr)), reply));
+    }
 
-    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
         println!("open {:?}", ino);
-        match self.index.i.get((ino - 1) as usize) {
-            None => {reply.error(ENOENT);},
-            Some(entry) => {
-                let mut fh = entry.inode;
-                while self.open_files.contains_key(&fh) {
-                    fh += 1;
+        let writes = flags as i32 & O_ACCMODE != O_RDONLY || flags as i32 & O_TRUNC != 0;
+        if !writes {
+            let image = match self.image(ino) {
+                Err(e) => return reply.error(e.errno()),
+                Ok((image, _)) => image,
+            };
+            let store      = self.store.clone();
+            let open_files = self.open_files.clone();
+            let traced     = self.trace.is_some();
+            return self.spawn(move || {
+                let fh = image.loaded(&store, 0, false).and_then(|index| {
+                    let index = index.as_ref().unwrap();
+                    let inode = try!(image.inode(index, ino));
+                    let handle = try!(handle_of(&index.i[inode as usize]));
+                    let path = if traced { Some(image.path(index, inode)) } else { None };
+                    Ok(add_open(&open_files, ino, handle, path))
+                });
+                match fh {
+                    Err(e) => reply.error(e.errno()),
+                    Ok(fh) => reply.opened(fh, 0),
                 }
-                self.open_files.insert(fh, Box::new(entry.chain(self.blockstore)));
-                reply.opened(fh, 0);
+            });
+        }
+
+        // opening for writes changes the overlay, which only the session thread does
+        let (slot, inode) = match self.load(ino) {
+            Err(e) => {
+                reply.error(e.errno());
+                return;
             },
+            Ok(r) => r,
         };
+        let store = self.store.clone();
+        let blockstore = &store.blockstore;
+        change!(self, reply, "open", |overlay, index| {
+            if flags as i32 & O_TRUNC != 0 {
+                overlay.truncate(index, blockstore, inode, 0)
+            } else {
+                overlay.copy_up(index, blockstore, inode)
+            }
+        });
+
+        let handle = handle_of(&self.index(slot).as_ref().unwrap().i[inode as usize]);
+        match handle {
+            Err(e) => reply.error(e.errno()),
+            Ok(handle) => {
+                let fh = self.add_handle(ino, handle);
+                reply.opened(fh, 0);
+            },
+        }
     }
     fn release(&mut self,  _req: &Request, ino: u64, fh: u64,  _flags: u32, 
                _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
         println!("close {:?}", ino);
-        self.open_files.remove(&fh);
+        self.open_files.lock().unwrap().remove(&fh);
         reply.ok();
     }
 
     fn read (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
-        //TODO: i dont know if offset can be different than the last returned read size
         println!("read {:?} {} {}", ino, offset, size);
 
-        let file = self.open_files.get_mut(&fh).unwrap();
+        let file = match self.open_files.lock().unwrap().get(&fh) {
+            None => {
+                reply.error(EBADF);
+                return;
+            },
+            Some(file) => file.clone(),
+        };
+        let store     = self.store.clone();
+        let cache     = self.cache.clone();
+        let trace     = self.trace.clone();
+        let readahead = self.readahead;
+        let job = move || {
+            match file.read(&cache, &store.blockstore, offset, size as usize, readahead) {
+                Ok(buf) => {
+                    if let (Some(trace), Some(path)) = (trace, file.path.as_ref()) {
+                        trace.lock().unwrap().read(path, offset, buf.len() as u64);
+                    }
+                    reply.data(&buf);
+                },
+                Err(e) => {
+                    println!("read {:?}: {}", ino, e);
+                    reply.error(e.errno());
+                },
+            }
+        };
+        self.spawn(job);
+    }
+
+    fn write (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+        let file = self.open_files.lock().unwrap().get(&fh).cloned();
+        let r = match file.as_ref().map(|f| &f.handle) {
+            Some(&Handle::Upper(ref f)) => f.write_all_at(data, offset),
+            _ => {
+                reply.error(EBADF);
+                return;
+            },
+        };
+        match r {
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+            Ok(()) => {
+                let (slot, inode) = from_ino(ino);
+                let mut index = self.index(slot);
+                let entry = &mut index.as_mut().unwrap().i[inode as usize];
+                entry.size = ::std::cmp::max(entry.size, offset + data.len() as u64);
+                reply.written(data.len() as u32);
+            },
+        }
+    }
+
+    fn destroy (&mut self, _req: &Request) {
+        println!("{}", self.cache.stats());
+        if let Some(ref trace) = self.trace {
+            trace.lock().unwrap().flush();
+        }
+    }
+
+    fn flush (&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        reply.ok();
+    }
 
-        let mut buf = vec![0; size as usize];
-        let r = file.read(&mut buf).unwrap();
-        reply.data(&buf[..r]);
+    fn fsync (&mut self, _req: &Request, _ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let file = self.open_files.lock().unwrap().get(&fh).cloned();
+        match file.as_ref().map(|f| &f.handle) {
+            Some(&Handle::Upper(ref f)) => match f.sync_all() {
+                Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+                Ok(()) => reply.ok(),
+            },
+            _ => reply.ok(),
+        }
     }
 
-    fn readdir (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, mut reply: ReplyDirectory) {
+    fn setattr (&mut self, _req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+        // the index has no owners or times, so only the mode and size can change. times are dropped
+        // like the owner the mount shows is, so touch, cp -p and tar keep working
+        if uid.map(|uid| uid != self.uid).unwrap_or(false) || gid.map(|gid| gid != self.gid).unwrap_or(false) {
+            reply.error(EPERM);
+            return;
+        }
+        if mode.is_some() || size.is_some() {
+            let inode = match self.writable_inode(ino) {
+                Err(e) => {
+                    reply.error(e.errno());
+                    return;
+                },
+                Ok(inode) => inode,
+            };
+            let store = self.store.clone();
+            let blockstore = &store.blockstore;
+            if let Some(mode) = mode {
+                change!(self, reply, "setattr", |overlay, index| overlay.chmod(index, inode, (mode & 0o7777) as u16));
+            }
+            if let Some(size) = size {
+                change!(self, reply, "setattr", |overlay, index| overlay.truncate(index, blockstore, inode, size));
+            }
+        }
+        match self.attr(ino) {
+            Err(e) => reply.error(e.errno()),
+            Ok(attr) => reply.attr(&TTL, &attr),
+        }
+    }
+
+    fn create (&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+        let name = name.to_string_lossy().into_owned();
+        let parent = match self.writable_inode(parent) {
+            Err(e) => {
+                reply.error(e.errno());
+                return;
+            },
+            Ok(inode) => inode,
+        };
+        let store = self.store.clone();
+        let blockstore = &store.blockstore;
+        let (inode, f) = change!(self, reply, "create", |overlay, index| {
+            overlay.create(index, blockstore, parent, &name, 2, (mode & 0o7777) as u16)
+                .and_then(|inode| upper_file(&index.i[inode as usize].host_path).map(|f| (inode, f)))
+        });
+        let fh = self.add_handle(to_ino(0, inode), Handle::Upper(f));
+        let attr = entry_to_file_attr(to_ino(0, inode), &self.index(0).as_ref().unwrap().i[inode as usize]);
+        reply.created(&TTL, &self.own(attr), 0, fh, flags);
+    }
+
+    fn mkdir (&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        let name = name.to_string_lossy().into_owned();
+        let parent = match self.writable_inode(parent) {
+            Err(e) => {
+                reply.error(e.errno());
+                return;
+            },
+            Ok(inode) => inode,
+        };
+        let store = self.store.clone();
+        let blockstore = &store.blockstore;
+        let inode = change!(self, reply, "mkdir", |overlay, index| overlay.create(index, blockstore, parent, &name, 1, (mode & 0o7777) as u16));
+        let attr = entry_to_file_attr(to_ino(0, inode), &self.index(0).as_ref().unwrap().i[inode as usize]);
+        reply.entry(&TTL, &self.own(attr), 0);
+    }
+
+    fn unlink (&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.remove(parent, name, false, reply);
+    }
+
+    fn rmdir (&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.remove(parent, name, true, reply);
+    }
+
+    fn rename (&mut self, _req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, reply: ReplyEmpty) {
+        let inodes = self.writable_inode(parent).and_then(|parent| self.writable_inode(newparent).map(|newparent| (parent, newparent)));
+        let (parent, newparent) = match inodes {
+            Err(e) => {
+                reply.error(e.errno());
+                return;
+            },
+            Ok(inodes) => inodes,
+        };
+        let store = self.store.clone();
+        let blockstore = &store.blockstore;
+        change!(self, reply, "rename", |overlay, index| {
+            overlay.rename(index, blockstore, parent, &name.to_string_lossy(), newparent, &newname.to_string_lossy())
+        });
+        reply.ok();
+    }
+
+    fn readdir (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, reply: ReplyDirectory) {
         println!("readdir {:?}", ino);
         if offset != 0 {
             reply.error(ENOENT);
             return;
         }
-        match self.index.i.get((ino - 1) as usize) {
-            None => reply.error(ENOENT),
-            Some(entry) => {
-                reply.add(1, 0, FileType::Directory, "."); //FIXME
-                reply.add(1, 1, FileType::Directory, "..");
-
-                let mut offset = 2;
-
-                match entry.dir {
-                    None => reply.ok(),
-                    Some(ref dir) => {
-                        for (s,d) in dir {
-                            reply.add(d.i, offset, match d.k {
-                                1 => FileType::Directory,
-                                _ => FileType::RegularFile,
-                            }, s);
-                            offset += 1;
-                        }
-                        reply.ok();
-                    }
-                };
+        let (image, store) = match self.image(ino) {
+            Err(_) => return reply_entries(ino, self.entries(ino), reply),
+            Ok((image, _)) => (image, self.store.clone()),
+        };
+        self.spawn(move || reply_entries(ino, image.entries(&store, ino), reply));
+    }
+}
+
+fn reply_entry(parent: u64, attr: Result<FileAttr, Error>, reply: ReplyEntry) {
+    match attr {
+        Err(e) => {
+            if let Error::NotFound(_) = e {} else {
+                println!("lookup {:?}: {}", parent, e);
             }
+            reply.error(e.errno());
+        },
+        Ok(attr) => reply.entry(&TTL, &attr, 0),
+    }
+}
+
+fn reply_attr(attr: Result<FileAttr, Error>, reply: ReplyAttr) {
+    match attr {
+        Err(e) => reply.error(e.errno()),
+        Ok(attr) => reply.attr(&TTL, &attr),
+    }
+}
+
+fn reply_entries(ino: u64, entries: Result<Vec<(u64, FileType, String)>, Error>, mut reply: ReplyDirectory) {
+    match entries {
+        Err(e) => {
+            println!("readdir {:?}: {}", ino, e);
+            reply.error(e.errno());
+        },
+        Ok(entries) => {
+            reply.add(1, 0, FileType::Directory, "."); //FIXME
+            reply.add(1, 1, FileType::Directory, "..");
+
+            let mut offset = 2;
+            for (ino, kind, name) in entries {
+                reply.add(ino, offset, kind, name);
+                offset += 1;
+            }
+            reply.ok();
         }
     }
 }
 
+impl Fuse {
+    fn remove(&mut self, parent: u64, name: &OsStr, dir: bool, reply: ReplyEmpty) {
+        let parent = match self.writable_inode(parent) {
+            Err(e) => {
+                reply.error(e.errno());
+                return;
+            },
+            Ok(inode) => inode,
+        };
+        let store = self.store.clone();
+        let blockstore = &store.blockstore;
+        change!(self, reply, "remove", |overlay, index| overlay.remove(index, blockstore, parent, &name.to_string_lossy(), dir));
+        reply.ok();
+    }
+}
+
+fn nix_error(what: &str, e: ::nix::Error) -> Error {
+    Error::Io(what.to_owned(), io::Error::from_raw_os_error(e.errno() as i32))
+}
+
+/// a background process, which tells the process that started it when its mount is ready
+pub struct Daemon {
+    ready: RawFd,
+}
+
+/// continue in a background process. the process calling this waits until the mount of the
+/// background process is ready and exits, with the exit code of an io error if it isn't
+pub fn daemon() -> Result<Daemon, Error> {
+    let (rx, tx) = try!(pipe().map_err(|e| nix_error("cannot start in the background", e)));
+    match try!(fork().map_err(|e| nix_error("cannot start in the background", e))) {
+        ForkResult::Parent{child} => {
+            let _ = close(tx);
+            let mut ready = [0];
+            if read(rx, &mut ready) == Ok(1) {
+                println!("mounted in the background by process {}", child);
+                ::std::process::exit(0);
+            }
+            ::std::process::exit(Error::Io(String::new(), io::Error::from_raw_os_error(EIO)).exit_code());
+        },
+        ForkResult::Child => {
+            let _ = close(rx);
+            try!(setsid().map_err(|e| nix_error("cannot start in the background", e)));
+            Ok(Daemon {
+                ready: tx,
+            })
+        },
+    }
+}
+
+/// mount fs at mountpoint until it is unmounted.
+/// in a daemon, output is dropped once the mount is ready
+pub fn mount(fs: Fuse, mountpoint: &Path, options: &[&OsStr], daemon: Option<Daemon>) -> Result<(), Error> {
+    let failed = |e| Error::Io(format!("cannot mount {}", mountpoint.display()), e);
+    let mut session = try!(Session::new(fs, mountpoint, options).map_err(&failed));
+    if let Some(daemon) = daemon {
+        if let Ok(null) = OpenOptions::new().write(true).open("/dev/null") {
+            let _ = dup2(null.as_raw_fd(), 1);
+            let _ = dup2(null.as_raw_fd(), 2);
+        }
+        let _ = write(daemon.ready, &[1]);
+        let _ = close(daemon.ready);
+    }
+    session.run().map_err(&failed)
+}
+
+/// unmount a mount, which ends the process serving it
+pub fn unmount(mountpoint: &Path) -> Result<(), Error> {
+    let out = match Command::new("fusermount").arg("-u").arg(mountpoint).output() {
+        // without fusermount only root can unmount, and doesn't need it
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return ::nix::mount::umount(mountpoint).map_err(|e| nix_error(&format!("cannot unmount {}", mountpoint.display()), e));
+        },
+        r => try!(r.map_err(|e| Error::Io("cannot run fusermount".to_owned(), e))),
+    };
+    if !out.status.success() {
+        let e = io::Error::new(io::ErrorKind::Other, String::from_utf8_lossy(&out.stderr).trim().to_owned());
+        return Err(Error::Io(format!("cannot unmount {}", mountpoint.display()), e));
+    }
+    Ok(())
+}
+
 impl Inode {
-    pub fn chain<'a>(&'a self, blockstore: &'a BlockStore) -> Chain<'a, Take<Chain<'a, Take<File>>>> {
-        let c = self.content.as_ref().unwrap();
-        let it = c.iter().map(move |c| {
+    /// content of a regular file, either inline in the index or from its blocks
+    pub fn reader<'a>(&self, blockstore: &'a BlockStore) -> Box<Read + 'a> {
+        match self.inline {
+            Some(ref inline) => Box::new(Cursor::new(inline.clone())),
+            None => Box::new(self.chain(blockstore)),
+        }
+    }
+
+    /// reads only borrow the store, so the index may grow while files are open
+    pub fn chain<'a>(&self, blockstore: &'a BlockStore) -> Chain<'a, Take<BlockReader<'a>>> {
+        let c = self.content.clone().unwrap();
+        let it = c.into_iter().map(move |c| {
             println!("reading from block {:?} offset  {} limit {}", c.h, c.o, c.l);
 
-            let block = blockstore.get(&c.h).expect("block not found");
-            let mut re = block.chain();
-            re.seek(SeekFrom::Current(c.o as i64)).unwrap();
-            Take::limit(re, c.l as usize)
+            if c.is_hole() {
+                return Take::limit(BlockReader::Zeros(0), c.l as usize);
+            }
+
+            let re = blockstore.read(&c.h).and_then(|mut re| {
+                try!(re.seek(SeekFrom::Current(c.o as i64)).map_err(|_| Error::CorruptBlock(c.h.clone())));
+                Ok(re)
+            });
+            match re {
+                Ok(re) => Take::limit(re, c.l as usize),
+                Err(e) => Take::limit(BlockReader::Failed(e.to_string()), c.l as usize),
+            }
 
         });
         Chain::new(Box::new(it))
     }
 }
+
+#[test]
+fn store_mount_serves_every_index() {
+    let dir = ::testing::temp_dir("fs");
+    let mut store = ::store::open_or_init(&dir.join("store")).unwrap();
+    store.store_stream(&b"hello"[..], "a", "one").unwrap();
+    let root = store.store_stream(&b"world"[..], "b", "two").unwrap();
+
+    let mut fs = Fuse::store(::std::sync::Arc::new(store), true, None);
+    let names: Vec<String> = fs.entries(to_ino(0, STORE_ROOT)).unwrap().into_iter().map(|(_, _, n)| n).collect();
+    assert_eq!(names, vec!["by-hash", "one", "two"]);
+    assert!(fs.images.iter().all(|i| i.index.read().unwrap().is_none()));
+
+    let two = fs.find(to_ino(0, STORE_ROOT), "two").unwrap();
+    assert_eq!(fs.attr(two).unwrap().kind, FileType::Directory);
+    let b = fs.find(two, "b").unwrap();
+    assert_eq!(fs.attr(b).unwrap().size, 5);
+    assert!(fs.find(two, "a").is_err());
+
+    let by_hash = fs.find(to_ino(0, BY_HASH), &root.to_hex()).unwrap();
+    assert!(fs.find(by_hash, "b").is_ok());
+    assert_eq!(fs.images.iter().filter(|i| i.index.read().unwrap().is_some()).count(), 2);
+
+    // a block that isn't the root of an index
+    let block = fs.store.load("two", None, false).unwrap().i[1].content.clone().unwrap()[0].h.clone();
+    let slots = fs.images.len();
+    assert!(fs.find(to_ino(0, BY_HASH), &block.to_hex()).is_err());
+    assert_eq!(fs.images.len(), slots);
+
+    fs.uid = 1234;
+    fs.root_perm = Some(0o750);
+    fs.read_only = true;
+    assert_eq!(fs.attr(b).unwrap().uid, 1234);
+    assert_eq!(fs.attr(b).unwrap().perm & 0o222, 0);
+    assert_eq!(fs.attr(to_ino(0, STORE_ROOT)).unwrap().perm, 0o550);
+}
+
+#[test]
+fn reads_at_any_offset_through_the_cache() {
+    let dir = ::testing::temp_dir("fs-read");
+    ::std::fs::create_dir_all(dir.join("host")).unwrap();
+    let mut seed: u32 = 2463534242;
+    let content: Vec<u8> = (0..40000).map(|_| {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed as u8
+    }).collect();
+    ::std::io::Write::write_all(&mut File::create(dir.join("host/f")).unwrap(), &content).unwrap();
+
+    let mut store = ::store::open_or_init(&dir.join("store")).unwrap();
+    store.store_tree(&dir.join("host"), "f", None, &::testing::options()).unwrap();
+    let mut index = store.load("f", None, true).unwrap();
+    let inode = index.lookup(&store.blockstore, "/f").unwrap();
+    let blocks = Blocks::new(index.i[inode as usize].content.clone().unwrap());
+    assert!(blocks.content.len() > 1);
+
+    let cache = ::cache::new(1024 * 1024, 1);
+    for &(offset, size) in &[(0, 4096), (4000, 20000), (39990, 4096), (40000, 10), (50000, 10)] {
+        let r = blocks.read(&cache, &store.blockstore, offset, size).unwrap();
+        let from = ::std::cmp::min(offset as usize, content.len());
+        assert_eq!(&r[..], &content[from..::std::cmp::min(from + size, content.len())]);
+    }
+    assert!(cache.stats().hits > 0);
+
+    // the same open file from several threads at once
+    let store   = Arc::new(store);
+    let cache   = Arc::new(cache);
+    let content = Arc::new(content);
+    let file = Arc::new(Open{handle: Handle::Blocks(blocks), path: Some("/f".to_owned())});
+    let threads: Vec<_> = (0..4).map(|t| {
+        let (store, cache, content, file) = (store.clone(), cache.clone(), content.clone(), file.clone());
+        thread::spawn(move || {
+            for i in 0..10 {
+                let offset = (t * 10 + i) * 997;
+                let r = file.read(&cache, &store.blockstore, offset as u64, 1000, 4).unwrap();
+                assert_eq!(&r[..], &content[offset..::std::cmp::min(offset + 1000, content.len())]);
+            }
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
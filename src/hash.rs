@@ -0,0 +1,155 @@
+use blake3;
+use sha2::{Sha256, Sha512Trunc256, Digest};
+use std::io::{self, Read};
+
+/// hash function used for block ids.
+/// ids are tagged multihash style: varint code, digest length, digest.
+/// untagged 32 byte ids come from stores written before tagging and are sha256
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HashAlgo {
+    Sha256,
+    Sha512_256,
+    Blake3,
+}
+
+impl HashAlgo {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashAlgo::Sha256     => "sha256",
+            HashAlgo::Sha512_256 => "sha512-256",
+            HashAlgo::Blake3     => "blake3",
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<HashAlgo> {
+        match name {
+            "sha256"     => Some(HashAlgo::Sha256),
+            "sha512-256" => Some(HashAlgo::Sha512_256),
+            "blake3"     => Some(HashAlgo::Blake3),
+            _ => None,
+        }
+    }
+
+    /// multihash code, as varint bytes
+    fn tag(&self) -> &'static [u8] {
+        match *self {
+            HashAlgo::Sha256     => &[0x12],
+            HashAlgo::Sha512_256 => &[0x95, 0x20],
+            HashAlgo::Blake3     => &[0x1e],
+        }
+    }
+
+    /// the algorithm of a tagged id
+    pub fn of(id: &[u8]) -> Option<HashAlgo> {
+        for algo in &[HashAlgo::Sha256, HashAlgo::Sha512_256, HashAlgo::Blake3] {
+            let tag = algo.tag();
+            if id.len() == tag.len() + 1 + 32 && id.starts_with(tag) && id[tag.len()] == 32 {
+                return Some(*algo);
+            }
+        }
+        None
+    }
+}
+
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512_256(Sha512Trunc256),
+    Blake3(blake3::Hasher),
+}
+
+impl Hasher {
+    pub fn new(algo: HashAlgo) -> Hasher {
+        match algo {
+            HashAlgo::Sha256     => Hasher::Sha256(Sha256::default()),
+            HashAlgo::Sha512_256 => Hasher::Sha512_256(Sha512Trunc256::default()),
+            HashAlgo::Blake3     => Hasher::Blake3(blake3::Hasher::new()),
+        }
+    }
+
+    pub fn algo(&self) -> HashAlgo {
+        match *self {
+            Hasher::Sha256(_)     => HashAlgo::Sha256,
+            Hasher::Sha512_256(_) => HashAlgo::Sha512_256,
+            Hasher::Blake3(_)     => HashAlgo::Blake3,
+        }
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        match *self {
+            Hasher::Sha256(ref mut h)     => h.input(data),
+            Hasher::Sha512_256(ref mut h) => h.input(data),
+            Hasher::Blake3(ref mut h)     => { h.update(data); },
+        }
+    }
+
+    /// the tagged id of everything input so far
+    pub fn result(self) -> Vec<u8> {
+        let algo = self.algo();
+        let digest = match self {
+            Hasher::Sha256(h)     => h.result().as_slice().to_vec(),
+            Hasher::Sha512_256(h) => h.result().as_slice().to_vec(),
+            Hasher::Blake3(h)     => h.finalize().as_bytes().to_vec(),
+        };
+        let mut id = algo.tag().to_vec();
+        id.push(digest.len() as u8);
+        id.extend_from_slice(&digest);
+        id
+    }
+
+    /// the tagged id so far, and start over
+    pub fn reset(&mut self) -> Vec<u8> {
+        let algo = self.algo();
+        ::std::mem::replace(self, Hasher::new(algo)).result()
+    }
+}
+
+pub fn digest(algo: HashAlgo, data: &[u8]) -> Vec<u8> {
+    let mut h = Hasher::new(algo);
+    h.input(data);
+    h.result()
+}
+
+pub fn digest_reader<R: Read>(algo: HashAlgo, r: &mut R) -> io::Result<Vec<u8>> {
+    let mut h = Hasher::new(algo);
+    let mut buf = [0; 4096];
+    loop {
+        let rs = try!(r.read(&mut buf));
+        if rs < 1 {
+            break;
+        }
+        h.input(&buf[..rs]);
+    }
+    Ok(h.result())
+}
+
+/// the tagged form of an id. untagged ids are legacy sha256
+pub fn normalize(id: &[u8]) -> Vec<u8> {
+    if id.len() == 32 {
+        let mut r = HashAlgo::Sha256.tag().to_vec();
+        r.push(32);
+        r.extend_from_slice(id);
+        r
+    } else {
+        id.to_vec()
+    }
+}
+
+/// the digest part of a tagged id
+pub fn digest_part(id: &[u8]) -> &[u8] {
+    &id[id.len().saturating_sub(32)..]
+}
+
+#[test]
+fn legacy_ids_are_sha256() {
+    let legacy = Sha256::digest(b"hello").as_slice().to_vec();
+    let tagged = digest(HashAlgo::Sha256, b"hello");
+    assert_eq!(normalize(&legacy), tagged);
+    assert_eq!(HashAlgo::of(&tagged), Some(HashAlgo::Sha256));
+    assert_eq!(digest_part(&tagged), &legacy[..]);
+
+    for algo in &[HashAlgo::Sha512_256, HashAlgo::Blake3] {
+        let id = digest(*algo, b"hello");
+        assert_eq!(HashAlgo::of(&id), Some(*algo));
+        assert_eq!(normalize(&id), id);
+    }
+}
@@ -1,7 +1,9 @@
+use error::Error;
 use serde::{Serialize, Serializer};
 use std::collections::{HashMap, BTreeMap};
 use std::path::Path;
 use std::fs::metadata;
+use std::os::unix::fs::MetadataExt;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Inode {
@@ -15,9 +17,40 @@ pub struct Inode {
     pub dir:     Option<HashMap<String, ContentDirEntry>>, //directory
     pub hash:    Option<String>, //file hash
     pub content: Option<Vec<ContentBlockEntry>>, //content blocks
+    pub inline:  Option<Vec<u8>>, //content of tiny files, instead of content blocks
+
+    #[serde(skip)]
+    pub host: HostMeta, //what the host reported when it was stored. kept next to the index, see save_host_meta
 
     #[serde(skip)]
     pub host_path: ::std::ffi::OsString, // full path. will not be stored
+    #[serde(skip)]
+    pub tree: Option<Vec<ContentBlockEntry>>, // blocks of the node of a directory that isn't loaded yet
+}
+
+/// what store --parent compares to tell whether a host file is unchanged.
+/// a file rewritten in place keeps its inode, and may keep its size and mtime seconds too
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct HostMeta {
+    pub mtime:      i64,
+    pub mtime_nsec: i64,
+    pub ctime:      i64,
+    pub ctime_nsec: i64,
+    pub dev:        u64,
+    pub inode:      u64,
+}
+
+impl HostMeta {
+    pub fn of(meta: &::std::fs::Metadata) -> HostMeta {
+        HostMeta {
+            mtime:      meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            ctime:      meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+            dev:        meta.dev(),
+            inode:      meta.ino(),
+        }
+    }
 }
 
 fn ordered_map<S>(value: &Option<HashMap<String, ContentDirEntry>>, serializer: S) -> Result<S::Ok, S::Error>
@@ -42,6 +75,21 @@ pub struct ContentBlockEntry {
     pub l: u64,     //length into block
 }
 
+impl ContentBlockEntry {
+    /// a run of zeros that has no block, like a hole in a sparse file
+    pub fn hole(len: u64) -> ContentBlockEntry {
+        ContentBlockEntry {
+            h: Vec::new(),
+            o: 0,
+            l: len,
+        }
+    }
+
+    pub fn is_hole(&self) -> bool {
+        self.h.is_empty()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ContentDirEntry {
     pub i: u64,     //inode
@@ -63,8 +111,8 @@ fn collect_dir(path: ::std::ffi::OsString) -> ::std::io::Result<Vec<::std::fs::D
 }
 
 impl Index {
-    fn add_from_dir_entry(&mut self, parent_inode: u64, path: ::std::fs::DirEntry) -> (String, ContentDirEntry) {
-        let meta = path.metadata().unwrap();
+    fn add_from_dir_entry(&mut self, parent_inode: u64, path: ::std::fs::DirEntry) -> ::std::io::Result<(String, ContentDirEntry)> {
+        let meta = try!(path.metadata());
         let i = (self.i.len()) as u64;
 
         let kind = match meta.is_dir() {
@@ -82,8 +130,12 @@ impl Index {
             dir:        None,
             hash:       None,
             content:    Some(Vec::new()),
+            inline:     None,
+
+            host: HostMeta::of(&meta),
 
             host_path: path.path().into_os_string(),
+            tree:      None,
         };
 
 
@@ -91,18 +143,18 @@ impl Index {
 
         self.i.push(entry);
 
-        (
+        Ok((
             path.file_name().to_string_lossy().into_owned(),
             ContentDirEntry {
                 i: i,
                 k: kind,
             },
-        )
+        ))
     }
 
-    fn descend(&mut self, parent_inode: u64, path: ::std::ffi::OsString) {
+    fn descend(&mut self, parent_inode: u64, path: ::std::ffi::OsString) -> Result<(), Error> {
 
-        let dirs = collect_dir(path).unwrap();
+        let dirs = try!(collect_dir(path.clone()).map_err(|e| Error::Io(format!("cannot read {:?}", path), e)));
 
         let inode_start = self.i.len() as u64;
         let inode_len   = dirs.len() as u64;
@@ -110,7 +162,8 @@ impl Index {
         // 1 iteration to create all the inodes
         let mut contentdirmap : HashMap<String, ContentDirEntry> = HashMap::new();
         for path in dirs {
-            let (name, cde) = self.add_from_dir_entry(parent_inode, path);
+            let p = path.path();
+            let (name, cde) = try!(self.add_from_dir_entry(parent_inode, path).map_err(|e| Error::Io(format!("cannot read {:?}", p), e)));
             contentdirmap.insert(name, cde);
         }
 
@@ -124,13 +177,34 @@ impl Index {
                 (e.kind, e.inode, e.host_path.clone())
             };
             if kind == 1 {
-                self.descend(inode, path);
+                try!(self.descend(inode, path));
+            }
+        }
+        Ok(())
+    }
+
+    /// maps the path of every inode relative to the root to its inode number
+    pub fn paths(&self) -> HashMap<String, u64> {
+        let mut r = HashMap::new();
+        let mut todo = vec![(String::new(), 0 as u64)];
+        while let Some((prefix, inode)) = todo.pop() {
+            let dir = match self.i.get(inode as usize).and_then(|e| e.dir.as_ref()) {
+                None => continue,
+                Some(dir) => dir,
+            };
+            for (name, e) in dir {
+                let path = prefix.clone() + "/" + name;
+                if e.k == 1 {
+                    todo.push((path.clone(), e.i));
+                }
+                r.insert(path, e.i);
             }
         }
+        r
     }
 }
 
-pub fn from_host(host: ::std::ffi::OsString) -> Index{
+pub fn from_host(host: ::std::ffi::OsString) -> Result<Index, Error> {
     let mut index = Index{
         v: 1,
         i: Vec::new(),
@@ -148,11 +222,15 @@ pub fn from_host(host: ::std::ffi::OsString) -> Index{
         dir: None,
         hash: None,
         content: None,
+        inline: None,
+
+        host: HostMeta::default(),
 
         host_path: host.clone(),
+        tree: None,
     });
 
-    let meta = metadata(host.clone()).unwrap();
+    let meta = try!(metadata(host.clone()).map_err(|e| Error::Io(format!("cannot read {:?}", host), e)));
     if meta.is_file() {
         let mut contentdirmap : HashMap<String, ContentDirEntry> = HashMap::new();
         contentdirmap.insert(Path::new(host.as_os_str()).file_name().unwrap().to_string_lossy().into_owned(),
@@ -171,12 +249,66 @@ pub fn from_host(host: ::std::ffi::OsString) -> Index{
             dir: None,
             hash: None,
             content: None,
+            inline: None,
+
+            host: HostMeta::of(&meta),
 
             host_path: host.clone(),
+            tree: None,
         });
     } else {
-        index.descend(0, host);
+        try!(index.descend(0, host));
     }
-    index
+    Ok(index)
 }
 
+
+/// index holding a single file, whose content is streamed in by store_stream
+pub fn from_stream(filename: &str) -> Index {
+    let mut contentdirmap : HashMap<String, ContentDirEntry> = HashMap::new();
+    contentdirmap.insert(filename.to_owned(), ContentDirEntry {
+        i: 1,
+        k: 2,
+    });
+
+    let mut index = Index{
+        v: 1,
+        i: Vec::new(),
+        c: None,
+    };
+    index.i.push(Inode{
+        inode:  0,
+        parent: 0,
+        size:   0,
+        kind:   1,
+        access: 0o775,
+
+        dir: Some(contentdirmap),
+        hash: None,
+        content: None,
+        inline: None,
+
+        host: HostMeta::default(),
+
+        host_path: ::std::ffi::OsString::new(),
+        tree: None,
+    });
+    index.i.push(Inode{
+        inode:  1,
+        parent: 0,
+        size:   0,
+        kind:   2,
+        access: 0o775,
+
+        dir: None,
+        hash: None,
+        content: Some(Vec::new()),
+        inline: None,
+
+        host: HostMeta::default(),
+
+        host_path: ::std::ffi::OsString::new(),
+        tree: None,
+    });
+    index
+}
@@ -0,0 +1,65 @@
+//! content addressable storage of system images.
+//!
+//! a tree is chunked into blocks in a `BlockStore`, and described by an `Index`
+//! whose directories are themselves blocks. `store::open` gives the usual entry points,
+//! which fail with an `error::Error`:
+//!
+//! ```no_run
+//! use std::io::Read;
+//! use std::path::Path;
+//!
+//! let mut store = archon::store::open_or_init(Path::new("/tmp/store")).unwrap();
+//! store.store_tree(Path::new("/usr"), "usr", None, &archon::serializer::StoreOptions{
+//!     jobs: 4,
+//!     inline: 0,
+//!     splitters: archon::splitter::default(),
+//! }).unwrap();
+//!
+//! let mut index = store.load("usr", None, true).unwrap();
+//! for (name, _) in index.read_dir(&store.blockstore, 0).unwrap() {
+//!     println!("{}", name);
+//! }
+//! let inode = index.lookup(&store.blockstore, "/bin/ls").unwrap();
+//! let mut content = Vec::new();
+//! index.open(&store.blockstore, inode).unwrap().read_to_end(&mut content).unwrap();
+//! ```
+
+extern crate blake3;
+extern crate digest;
+extern crate fuse;
+extern crate generic_array;
+extern crate hex;
+extern crate libc;
+extern crate nix;
+extern crate pbr;
+extern crate ring;
+extern crate rmp_serde as rmps;
+extern crate rollsum;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate sha2;
+extern crate tempfile;
+extern crate time;
+extern crate elfkit;
+extern crate byteorder;
+
+pub mod annotate;
+pub mod blockstore;
+pub mod cache;
+pub mod chunker;
+pub mod crypt;
+pub mod error;
+pub mod extract;
+pub mod fs;
+pub mod hash;
+pub mod index;
+pub mod overlay;
+pub mod readchain;
+pub mod schema;
+pub mod serializer;
+pub mod sign;
+pub mod splitter;
+pub mod store;
+#[cfg(test)] mod testing;
+pub mod trace;
+pub mod tree;
@@ -1,37 +1,174 @@
+extern crate archon;
 extern crate clap;
-extern crate digest;
-extern crate fuse;
-extern crate generic_array;
 extern crate hex;
-extern crate libc;
-extern crate pbr;
-extern crate rmp_serde as rmps;
-extern crate rollsum;
-extern crate serde;
-#[macro_use] extern crate serde_derive;
-extern crate sha2;
-extern crate tempfile;
-extern crate time;
-extern crate url;
-#[macro_use] extern crate elfkit;
-extern crate byteorder;
-
-mod blockstore;
-mod chunker;
-mod fs;
-mod index;
-mod readchain;
-mod serializer;
 
+use archon::{annotate, cache, fs, hash, serializer, sign, splitter, store, trace};
+use archon::error::Error;
 use clap::{Arg, App, SubCommand, AppSettings};
-use hex::ToHex;
+use hex::{ToHex, FromHex};
 use std::env;
 use std::ffi::OsStr;
-use std::ffi::OsString;
-use std::fs::{create_dir_all};
 use std::path::Path;
-use url::{Url};
-use elfkit::types;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// a numeric argument, if it was given
+fn number<T: FromStr>(submatches: &clap::ArgMatches, arg: &str) -> Option<T> {
+    submatches.value_of(arg).map(|n| {
+        or_exit(n.parse().map_err(|_| Error::Invalid(format!("{} must be a number", arg))))
+    })
+}
+
+fn jobs(submatches: &clap::ArgMatches) -> usize {
+    number(submatches, "jobs").unwrap_or(1)
+}
+
+/// move to the background if asked to, before any threads are started
+fn daemon(submatches: &clap::ArgMatches) -> Option<fs::Daemon> {
+    match submatches.is_present("background") {
+        true  => Some(or_exit(fs::daemon())),
+        false => None,
+    }
+}
+
+/// size the block cache of a mount and start its threads, by its arguments
+fn tune(fs: &mut fs::Fuse, blockstore: &archon::blockstore::BlockStore, submatches: &clap::ArgMatches) {
+    let number = |arg: &str, default: usize| number(submatches, arg).unwrap_or(default);
+
+    let mut cache = cache::new(number("cache-size", cache::DEFAULT_SIZE / 1024 / 1024) * 1024 * 1024,
+                               number("open-files", cache::DEFAULT_FILES));
+    cache.start_prefetch(blockstore, number("prefetch-threads", 2));
+    fs.set_cache(cache);
+    fs.set_workers(number("threads", 4));
+    fs.readahead = number("readahead", 16);
+
+    if let Some(trace) = submatches.value_of("trace") {
+        fs.set_trace(or_exit(trace::record(Path::new(trace))));
+    }
+    if let Some(trace) = submatches.value_of("warm") {
+        let accesses = or_exit(trace::load(Path::new(trace)));
+        let found = fs.warm(&accesses);
+        println!("warming {} of {} reads from {}", found, accesses.len(), trace);
+    }
+}
+
+/// set how a mount shows its files by its arguments, and return the options to mount it with
+fn present(fs: &mut fs::Fuse, submatches: &clap::ArgMatches) -> String {
+    if let Some(uid) = number(submatches, "uid") {
+        fs.uid = uid;
+    }
+    if let Some(gid) = number(submatches, "gid") {
+        fs.gid = gid;
+    }
+    if let Some(mode) = submatches.value_of("root-mode") {
+        fs.root_perm = Some(or_exit(u16::from_str_radix(mode, 8).ok().filter(|m| *m <= 0o7777)
+                                    .ok_or(Error::Invalid("root-mode must be octal permissions".to_owned()))));
+    }
+    fs.read_only = submatches.is_present("read-only");
+
+    let mut options = vec!["auto_unmount"];
+    for &(arg, option) in &[("allow-other", "allow_other"), ("default-permissions", "default_permissions"), ("read-only", "ro")] {
+        if submatches.is_present(arg) {
+            options.push(option);
+        }
+    }
+    options.extend(submatches.values_of("option").into_iter().flat_map(|o| o));
+    options.join(",")
+}
+
+/// the arguments of mount and mount-all
+fn mount_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("cache-size")
+            .long("cache-size")
+            .help("megabytes of block content to keep in memory")
+            .takes_value(true),
+        Arg::with_name("open-files")
+            .long("open-files")
+            .help("number of block files to keep open")
+            .takes_value(true),
+        Arg::with_name("readahead")
+            .long("readahead")
+            .help("number of blocks to load ahead of sequential reads, 16 by default")
+            .takes_value(true),
+        Arg::with_name("prefetch-threads")
+            .long("prefetch-threads")
+            .help("number of threads loading blocks ahead of reads, 2 by default")
+            .takes_value(true),
+        Arg::with_name("warm")
+            .long("warm")
+            .help("load the blocks of the reads in this trace when mounting")
+            .takes_value(true),
+        Arg::with_name("trace")
+            .long("trace")
+            .help("record every read to this file, for relayout or warm")
+            .takes_value(true),
+        Arg::with_name("threads")
+            .long("threads")
+            .help("number of threads serving reads, 4 by default. 0 serves them with every other request")
+            .takes_value(true),
+        Arg::with_name("option")
+            .long("option")
+            .short("o")
+            .help("pass this option to fuse, like -o max_read=131072")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("uid")
+            .long("uid")
+            .help("owner of every file, the mounting user by default")
+            .takes_value(true),
+        Arg::with_name("gid")
+            .long("gid")
+            .help("group of every file, the mounting user's by default")
+            .takes_value(true),
+        Arg::with_name("root-mode")
+            .long("root-mode")
+            .help("octal permissions of the mountpoint, like 755")
+            .takes_value(true),
+        Arg::with_name("allow-other")
+            .long("allow-other")
+            .help("let other users access the mount. needs user_allow_other in /etc/fuse.conf"),
+        Arg::with_name("default-permissions")
+            .long("default-permissions")
+            .help("have the kernel check file permissions against the owner and mode"),
+        Arg::with_name("read-only")
+            .long("read-only")
+            .help("mount read only and show no write permissions"),
+        Arg::with_name("foreground")
+            .long("foreground")
+            .short("f")
+            .help("serve the mount from this process until it is unmounted, the default")
+            .conflicts_with("background"),
+        Arg::with_name("background")
+            .long("background")
+            .help("serve the mount from a background process once it is ready. stop it with unmount"),
+    ]
+}
+
+/// load an index by name, or by its root hash when there is no index of that name
+fn load(store: &store::Store, name: &str, pubkey: Option<&str>, lazy: bool) -> Result<archon::index::Index, Error> {
+    if pubkey.is_none() && !store.path.join(name).exists() {
+        if let Ok(root) = Vec::<u8>::from_hex(name) {
+            return store.load_hash(&root, lazy);
+        }
+    }
+    store.load(name, pubkey, lazy).map_err(|e| {
+        println!("refusing index {:?}", name);
+        e
+    })
+}
+
+/// print an error and exit with its code, for results of the library
+fn or_exit<T>(r: Result<T, Error>) -> T {
+    match r {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{}", e);
+            ::std::process::exit(e.exit_code());
+        },
+    }
+}
 
 fn main() {
 
@@ -41,6 +178,25 @@ fn main() {
         .setting(AppSettings::DisableHelpSubcommand)
         .version("1.0")
         .about("content addressable image indexer")
+        .subcommand(
+            SubCommand::with_name("init")
+            .about("create a store, choosing the hash function for its blocks")
+            .arg(Arg::with_name("hash")
+                 .long("hash")
+                 .help("hash function for block ids")
+                 .takes_value(true)
+                 .default_value("sha256")
+                 .possible_values(&["sha256", "sha512-256", "blake3"])
+                )
+            .arg(Arg::with_name("encrypt")
+                 .long("encrypt")
+                 .help("encrypt block content and names with a new store secret")
+                )
+            .arg(Arg::with_name("per-file")
+                 .long("per-file")
+                 .help("chunk every file on its own, so blocks never span files and unchanged directories share their blocks between indices")
+                )
+            )
         .subcommand(
             SubCommand::with_name("rm")
             .about("remove index from store")
@@ -56,7 +212,7 @@ fn main() {
             .about("write image into content store")
             .arg(Arg::with_name("root")
                  .required(true)
-                 .help("build image from this path")
+                 .help("build image from this path, or - to read a single file from stdin")
                  .takes_value(true)
                  .index(1)
                 )
@@ -66,13 +222,141 @@ fn main() {
                  .takes_value(true)
                  .index(2)
                 )
+            .arg(Arg::with_name("parent")
+                 .long("parent")
+                 .help("reuse content of unchanged files from this index")
+                 .takes_value(true)
+                )
+            .arg(Arg::with_name("filename")
+                 .long("filename")
+                 .help("name of the file when reading from stdin. defaults to the index name")
+                 .takes_value(true)
+                )
+            .arg(Arg::with_name("jobs")
+                 .long("jobs")
+                 .short("j")
+                 .help("number of threads chunking files")
+                 .takes_value(true)
+                )
+            .arg(Arg::with_name("inline")
+                 .long("inline")
+                 .help("store files smaller than this many bytes in the index instead of blocks")
+                 .takes_value(true)
+                )
+            .arg(Arg::with_name("split")
+                 .long("split")
+                 .help("content aware splitters to cut files with, in order. defaults to elf-cut-section")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .possible_values(&["elf-cut-section", "elf-sections", "none"])
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("chunkstats")
+            .about("compare dedup of chunking a tree as one stream or per file")
+            .arg(Arg::with_name("root")
+                 .required(true)
+                 .help("chunk this path")
+                 .takes_value(true)
+                 .index(1)
+                )
+            .arg(Arg::with_name("jobs")
+                 .long("jobs")
+                 .short("j")
+                 .help("number of threads chunking files")
+                 .takes_value(true)
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("extract")
+            .about("write image contents to a directory")
+            .arg(Arg::with_name("name")
+                 .required(true)
+                 .help("name of index, or the hash of its root")
+                 .takes_value(true)
+                 .index(1)
+                )
+            .arg(Arg::with_name("target")
+                 .required(true)
+                 .help("path where to write the image contents")
+                 .takes_value(true)
+                 .index(2)
+                )
+            .arg(Arg::with_name("require-signature")
+                 .long("require-signature")
+                 .help("refuse the index unless it is signed by this hex encoded ed25519 public key")
+                 .takes_value(true)
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("elf-annotate")
+            .about("copy an ELF file, adding a section with cut points for the elf-cut-section splitter")
+            .arg(Arg::with_name("in")
+                 .required(true)
+                 .help("ELF file to annotate")
+                 .takes_value(true)
+                 .index(1)
+                )
+            .arg(Arg::with_name("out")
+                 .required(true)
+                 .help("where to write the annotated copy")
+                 .takes_value(true)
+                 .index(2)
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("elf-inspect")
+            .about("print the cut points an ELF file carries")
+            .arg(Arg::with_name("file")
+                 .required(true)
+                 .help("ELF file to inspect")
+                 .takes_value(true)
+                 .index(1)
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("keygen")
+            .about("create an ed25519 secret key for signing indices and print its public key")
+            .arg(Arg::with_name("key")
+                 .required(true)
+                 .help("where to write the secret key")
+                 .takes_value(true)
+                 .index(1)
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("sign")
+            .about("sign the root hash of an index")
+            .arg(Arg::with_name("name")
+                 .required(true)
+                 .help("name of index")
+                 .takes_value(true)
+                 .index(1)
+                )
+            .arg(Arg::with_name("key")
+                 .long("key")
+                 .required(true)
+                 .help("secret key created by keygen")
+                 .takes_value(true)
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("migrate")
+            .about("rewrite an index as the current index version")
+            .arg(Arg::with_name("name")
+                 .required(true)
+                 .help("name of index")
+                 .takes_value(true)
+                 .index(1)
+                )
             )
         .subcommand(
             SubCommand::with_name("mount")
             .about("fuse mount image at a given destination")
             .arg(Arg::with_name("name")
                  .required(true)
-                 .help("name of index")
+                 .help("name of index, or the hash of its root")
                  .takes_value(true)
                  .index(1)
                 )
@@ -82,10 +366,134 @@ fn main() {
                  .takes_value(true)
                  .index(2)
                 )
+            .arg(Arg::with_name("require-signature")
+                 .long("require-signature")
+                 .help("refuse the index unless it is signed by this hex encoded ed25519 public key")
+                 .takes_value(true)
+                )
+            .arg(Arg::with_name("writable")
+                 .long("writable")
+                 .help("record changes in an overlay in the store, to be committed as a new index")
+                 .conflicts_with("read-only")
+                )
+            .args(&mount_args())
+            )
+        .subcommand(
+            SubCommand::with_name("mount-all")
+            .about("fuse mount every index of the store, each in a directory named after it")
+            .arg(Arg::with_name("target")
+                 .required(true)
+                 .help("path where to mount the store")
+                 .takes_value(true)
+                 .index(1)
+                )
+            .arg(Arg::with_name("by-hash")
+                 .long("by-hash")
+                 .help("also serve every index by the hash of its root, below by-hash/")
+                )
+            .arg(Arg::with_name("require-signature")
+                 .long("require-signature")
+                 .help("refuse indices unless they are signed by this hex encoded ed25519 public key")
+                 .takes_value(true)
+                )
+            .args(&mount_args())
+            )
+        .subcommand(
+            SubCommand::with_name("unmount")
+            .about("unmount a mount, stopping the process serving it")
+            .arg(Arg::with_name("target")
+                 .required(true)
+                 .help("where the mount is")
+                 .takes_value(true)
+                 .index(1)
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("relayout")
+            .about("store the blocks read in a trace of a mount one after another, so the same reads are sequential")
+            .arg(Arg::with_name("name")
+                 .required(true)
+                 .help("name of the index that was mounted")
+                 .takes_value(true)
+                 .index(1)
+                )
+            .arg(Arg::with_name("trace")
+                 .long("trace")
+                 .required(true)
+                 .help("trace recorded with mount --trace")
+                 .takes_value(true)
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("commit")
+            .about("store an index mounted writable with its changes as a new index")
+            .arg(Arg::with_name("mount")
+                 .required(true)
+                 .help("where the index is, or was, mounted")
+                 .takes_value(true)
+                 .index(1)
+                )
+            .arg(Arg::with_name("name")
+                 .required(true)
+                 .help("name of the new index")
+                 .takes_value(true)
+                 .index(2)
+                )
+            .arg(Arg::with_name("jobs")
+                 .long("jobs")
+                 .short("j")
+                 .help("number of threads chunking files")
+                 .takes_value(true)
+                )
             )
         .get_matches();
 
 
+    // commands that don't need a store
+    match matches.subcommand() {
+        ("elf-annotate", Some(submatches)) =>{
+            let input  = submatches.value_of("in").unwrap();
+            let output = submatches.value_of("out").unwrap();
+            let cuts   = or_exit(annotate::annotate(Path::new(input), Path::new(output)));
+            println!("wrote {} with {} cuts", output, cuts.len());
+            return;
+        },
+        ("elf-inspect", Some(submatches)) =>{
+            use splitter::Splitter;
+            let file = submatches.value_of("file").unwrap();
+            let mut f = or_exit(::std::fs::File::open(file).map_err(|e| Error::Io(format!("cannot read {}", file), e)));
+            let len = or_exit(f.metadata().map_err(|e| Error::Io(format!("cannot read {}", file), e))).len() as usize;
+            match splitter::ElfCutSection.cuts(&mut f) {
+                None => {
+                    println!("{} carries no cut section", file);
+                    ::std::process::exit(1);
+                },
+                Some(Err(e)) => {
+                    println!("{} carries a broken cut section: {}", file, e);
+                    ::std::process::exit(1);
+                },
+                Some(Ok(cuts)) => {
+                    println!("{} carries {} cuts", file, cuts.len());
+                    let mut at = 0;
+                    for cut in cuts {
+                        println!("0x{:08x} {:>10} bytes since previous cut{}", cut,
+                                 cut as i64 - at as i64,
+                                 if cut > len { " (beyond end of file)" } else { "" });
+                        at = cut;
+                    }
+                },
+            }
+            return;
+        },
+        ("keygen", Some(submatches)) =>{
+            let key = submatches.value_of("key").unwrap();
+            let pubkey = or_exit(sign::keygen(Path::new(key)));
+            println!("wrote {} with public key {}", key, pubkey.to_hex());
+            return;
+        },
+        _ => {},
+    }
+
     let key = "ARCHON_STORE";
     let content_store_path = match env::var(key) {
         Ok(val) => {
@@ -99,50 +507,154 @@ fn main() {
     };
 
     match matches.subcommand() {
+        ("init", Some(submatches)) =>{
+            let algo    = hash::HashAlgo::by_name(submatches.value_of("hash").unwrap()).unwrap();
+            let store   = or_exit(store::init(Path::new(&content_store_path), algo, submatches.is_present("encrypt"),
+                                              submatches.is_present("per-file")));
+            println!("initialized {}store with {} block ids, chunking {}",
+                     if store.encrypted() { "encrypted " } else { "" }, algo.name(),
+                     if store.per_file { "per file" } else { "as a stream" });
+        },
         ("store", Some(submatches)) =>{
+            let root_path = submatches.value_of("root").unwrap();
+            let name      = submatches.value_of("name").unwrap();
+            let mut store = or_exit(store::open_or_init(Path::new(&content_store_path)));
 
-            let root_path  = submatches.value_of("root").unwrap();
-            let name       = submatches.value_of("name").unwrap();
-            let store_path = Path::new(&content_store_path);
-            let bsp = store_path.join("content");
+            let root = if root_path == "-" {
+                let filename = submatches.value_of("filename").unwrap_or(name);
+                let stdin = ::std::io::stdin();
+                store.store_stream(stdin.lock(), filename, name)
+            } else {
+                let opts = serializer::StoreOptions {
+                    jobs:     jobs(submatches),
+                    inline:   number(submatches, "inline").unwrap_or(0),
+                    splitters: match submatches.values_of("split") {
+                        None => splitter::default(),
+                        Some(names) => names.filter_map(splitter::by_name).collect(),
+                    },
+                };
+                store.store_tree(Path::new(root_path), name, submatches.value_of("parent"), &opts)
+            };
 
-            create_dir_all(&bsp);
-            let mut bs = blockstore::new(bsp.to_str().unwrap().to_owned());
-
-            let mut hi = index::from_host(OsString::from(root_path));
-            hi.store_inodes(&mut bs);
+            println!("input stored into index {} with name {:?}", or_exit(root).to_hex(), name);
+        },
+        ("chunkstats", Some(submatches)) =>{
+            let root_path = submatches.value_of("root").unwrap();
+            let store     = or_exit(store::open_or_init(Path::new(&content_store_path)));
+            let hi        = or_exit(archon::index::from_host(root_path.into()));
 
-            loop {
-                hi = hi.store_index(&mut bs);
-                if hi.c.as_ref().unwrap().len() == 1 {
-                    break;
-                }
+            serializer::ChunkStats::print_header();
+            for &(mode, per_file) in &[("stream", false), ("per-file", true)] {
+                or_exit(hi.chunk_stats(&store.blockstore, per_file, &serializer::StoreOptions {
+                    jobs:     jobs(submatches),
+                    inline:   0,
+                    splitters: Vec::new(),
+                })).print(mode);
             }
-
-            hi.save_to_file(&store_path.join(name));
-            println!("input stored into index {} with name {:?}",
-                     hi.c.as_ref().unwrap().first().unwrap().h.to_hex(),
-                     name
-                     )
         },
         ("mount", Some(submatches)) =>{
             let name        = submatches.value_of("name").unwrap();
             let target_path = submatches.value_of("target").unwrap();
-            let store_path  = Path::new(&content_store_path);
-            let bsp = store_path.join("content");
+            let store       = or_exit(store::open(Path::new(&content_store_path)));
+            let hi = or_exit(load(&store, name, submatches.value_of("require-signature"), true));
 
-            let mut hi = index::Index::load_from_file(&store_path.join(name));
-            let bs = blockstore::new(bsp.to_str().unwrap().to_owned());
-            while let Some(_) = hi.c.as_ref() {
-                hi = hi.load_index(&bs);
+            println!("mounting index {:?} with {} inodes to {}", name, hi.i.len(), target_path);
+
+            let (overlay, _writing) = match submatches.is_present("writable") {
+                true  => {
+                    let (overlay, lock) = or_exit(store.overlay(Path::new(target_path), name));
+                    (Some(overlay), Some(lock))
+                },
+                false => (None, None),
+            };
+            let _lock = or_exit(store.lock_mounted());
+            let daemon = daemon(submatches);
+            let store = Arc::new(store);
+            let mut fs = match overlay {
+                Some(overlay) => {
+                    println!("recording changes in {}", overlay.path.display());
+                    or_exit(fs::Fuse::writable(hi, store.clone(), overlay))
+                },
+                None => fs::Fuse::new(hi, store.clone()),
+            };
+            tune(&mut fs, &store.blockstore, submatches);
+            let options = present(&mut fs, submatches);
+            or_exit(fs::mount(fs, Path::new(target_path), &[OsStr::new("-o"), OsStr::new(&options)], daemon));
+        }
+        ("mount-all", Some(submatches)) =>{
+            let target_path = submatches.value_of("target").unwrap();
+            let store       = or_exit(store::open(Path::new(&content_store_path)));
+            let pubkey      = submatches.value_of("require-signature");
+            if pubkey.is_some() && submatches.is_present("by-hash") {
+                // a root hash has no name, so there is no signature to check
+                or_exit::<()>(Err(Error::Invalid("--by-hash cannot be used with --require-signature".to_owned())));
             }
 
-            println!("mounting index {:?} with {} inodes to {}", name, hi.i.len(), target_path);
+            println!("mounting store {} to {}", content_store_path, target_path);
 
-            let fs = fs::Fuse::new(&hi, &bs);
-            let fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
-            fuse::mount(fs, &target_path, &fuse_args).unwrap();
+            let _lock = or_exit(store.lock_mounted());
+            let daemon = daemon(submatches);
+            let store = Arc::new(store);
+            let mut fs = fs::Fuse::store(store.clone(), submatches.is_present("by-hash"), pubkey.map(|k| k.to_owned()));
+            tune(&mut fs, &store.blockstore, submatches);
+            let options = present(&mut fs, submatches);
+            or_exit(fs::mount(fs, Path::new(target_path), &[OsStr::new("-o"), OsStr::new(&options)], daemon));
+        }
+        ("unmount", Some(submatches)) =>{
+            let target_path = submatches.value_of("target").unwrap();
+            or_exit(fs::unmount(Path::new(target_path)));
+        }
+        ("relayout", Some(submatches)) =>{
+            let name      = submatches.value_of("name").unwrap();
+            let trace     = submatches.value_of("trace").unwrap();
+            let mut store = or_exit(store::open(Path::new(&content_store_path)));
+            let accesses  = or_exit(trace::load(Path::new(trace)));
+            let (blocks, used) = or_exit(store.relayout(name, &accesses));
+            println!("packed {} blocks of {} of {} reads for index {:?}", blocks, used, accesses.len(), name);
         }
+        ("commit", Some(submatches)) =>{
+            let mount     = submatches.value_of("mount").unwrap();
+            let name      = submatches.value_of("name").unwrap();
+            let mut store = or_exit(store::open(Path::new(&content_store_path)));
+
+            let root = or_exit(store.commit(Path::new(mount), name, &serializer::StoreOptions {
+                jobs:      jobs(submatches),
+                inline:    0,
+                splitters: splitter::default(),
+            }));
+            println!("changes on {} stored into index {} with name {:?}", mount, root.to_hex(), name);
+        },
+        ("extract", Some(submatches)) =>{
+            let name        = submatches.value_of("name").unwrap();
+            let target_path = submatches.value_of("target").unwrap();
+            let store       = or_exit(store::open(Path::new(&content_store_path)));
+            let hi = or_exit(load(&store, name, submatches.value_of("require-signature"), false));
+
+            println!("extracting index {:?} with {} inodes to {}", name, hi.i.len(), target_path);
+            or_exit(hi.extract(&store.blockstore, Path::new(target_path)));
+        },
+        ("sign", Some(submatches)) =>{
+            let name  = submatches.value_of("name").unwrap();
+            let key   = Path::new(submatches.value_of("key").unwrap());
+            let store = or_exit(store::open(Path::new(&content_store_path)));
+
+            let (root, pubkey) = or_exit(store.sign(name, key));
+            println!("signed index {} with name {:?} by {}", root.to_hex(), name, pubkey.to_hex());
+        },
+        ("migrate", Some(submatches)) =>{
+            let name      = submatches.value_of("name").unwrap();
+            let mut store = or_exit(store::open(Path::new(&content_store_path)));
+
+            let (v, root, signed) = or_exit(store.migrate(name));
+            if v == archon::schema::CURRENT {
+                println!("index {} with name {:?} is already version {}", root.to_hex(), name, v);
+            } else {
+                println!("migrated index with name {:?} from version {} to {} as {}", name, v, archon::schema::CURRENT, root.to_hex());
+            }
+            if signed {
+                println!("removed its signature, which doesn't cover the migrated index. sign it again with: sign {} --key <key>", name);
+            }
+        },
         ("rm", Some(submatches)) =>{
             let name = submatches.value_of("name").unwrap();
 
@@ -0,0 +1,418 @@
+use blockstore::BlockStore;
+use error::{Error, Result};
+use index::{Index, Inode, HostMeta, ContentDirEntry};
+use libc::{EEXIST, EISDIR, ENOTDIR, ENOTEMPTY, EXDEV};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// a writable layer over a mounted index.
+///
+/// changed and new files live in <overlay>/files at their path in the image,
+/// removed paths are listed in <overlay>/whiteouts and changed permissions in <overlay>/modes.
+/// <overlay>/base holds the root hash
+/// of the index below, so the changes can be applied onto it again, to remount or to commit them.
+///
+/// directories can't be renamed, since that would mean copying up everything below them.
+/// like overlayfs, that fails with EXDEV, which makes mv fall back to copying
+pub struct Overlay {
+    pub path:  PathBuf,
+    pub base:  String, //hex root hash of the index below
+    whiteouts: BTreeSet<String>,
+    modes:     BTreeMap<String, u16>, //permissions by path, set by create, mkdir and chmod
+}
+
+fn errno(what: String, errno: i32) -> Error {
+    Error::Io(what, io::Error::from_raw_os_error(errno))
+}
+
+fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        None => ("", path),
+        Some(at) => (&path[..at], &path[at + 1..]),
+    }
+}
+
+/// the overlay at path, created for base if there is none yet
+pub fn open(path: &Path, base: &str) -> Result<Overlay> {
+    if path.join("base").exists() {
+        let overlay = try!(load(path));
+        if overlay.base != base {
+            return Err(Error::Invalid(format!("{} holds changes to index {}, not {}", path.display(), overlay.base, base)));
+        }
+        return Ok(overlay);
+    }
+    try!(fs::create_dir_all(path.join("files")).map_err(|e| Error::Io(format!("cannot create {}", path.display()), e)));
+    try!(File::create(path.join("base")).and_then(|mut f| writeln!(f, "{}", base))
+         .map_err(|e| Error::Io(format!("cannot write {}", path.display()), e)));
+    Ok(Overlay{
+        path:      path.to_owned(),
+        base:      base.to_owned(),
+        whiteouts: BTreeSet::new(),
+        modes:     BTreeMap::new(),
+    })
+}
+
+/// the overlay at path as a mount left it
+pub fn load(path: &Path) -> Result<Overlay> {
+    let mut base = String::new();
+    try!(File::open(path.join("base")).and_then(|mut f| f.read_to_string(&mut base)).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::NotFound(format!("overlay {}", path.display())),
+        _ => Error::Io(format!("cannot read {}", path.display()), e),
+    }));
+    let whiteouts = try!(read_lines(path, "whiteouts"));
+    let mut modes = BTreeMap::new();
+    for line in try!(read_lines(path, "modes")) {
+        let mut parts = line.splitn(2, ' ');
+        let mode = parts.next().and_then(|mode| u16::from_str_radix(mode, 8).ok());
+        match (mode, parts.next()) {
+            (Some(mode), Some(path)) => modes.insert(path.to_owned(), mode),
+            _ => return Err(Error::Invalid(format!("bad line {:?} in {}", line, path.join("modes").display()))),
+        };
+    }
+    Ok(Overlay{
+        path:      path.to_owned(),
+        base:      base.trim().to_owned(),
+        whiteouts: whiteouts.into_iter().collect(),
+        modes:     modes,
+    })
+}
+
+/// the lines of a file of an overlay, none if there is no such file
+fn read_lines(path: &Path, name: &str) -> Result<Vec<String>> {
+    let mut s = String::new();
+    if let Ok(mut f) = File::open(path.join(name)) {
+        try!(f.read_to_string(&mut s).map_err(|e| Error::Io(format!("cannot read {}", path.display()), e)));
+    }
+    Ok(s.lines().map(|l| l.to_owned()).collect())
+}
+
+impl Overlay {
+    /// where a path of the image lives in the upper layer
+    fn upper(&self, path: &str) -> PathBuf {
+        self.path.join("files").join(path.trim_left_matches('/'))
+    }
+
+    fn save_lines<I: Iterator<Item=String>>(&self, name: &str, lines: I) -> Result<()> {
+        let tmp = self.path.join(format!("{}.new", name));
+        try!(File::create(&tmp).and_then(|mut f| {
+            for line in lines {
+                try!(writeln!(f, "{}", line));
+            }
+            Ok(())
+        }).and_then(|_| fs::rename(&tmp, self.path.join(name)))
+        .map_err(|e| Error::Io(format!("cannot write {}", self.path.display()), e)));
+        Ok(())
+    }
+
+    fn save_whiteouts(&self) -> Result<()> {
+        self.save_lines("whiteouts", self.whiteouts.iter().cloned())
+    }
+
+    fn save_modes(&self) -> Result<()> {
+        self.save_lines("modes", self.modes.iter().map(|(path, mode)| format!("{:o} {}", mode, path)))
+    }
+
+    /// remove whatever the upper layer holds at a path
+    fn remove_upper(&self, path: &str) -> Result<()> {
+        let host = self.upper(path);
+        let r = match fs::symlink_metadata(&host) {
+            Err(_) => return Ok(()),
+            Ok(ref meta) if meta.is_dir() => fs::remove_dir_all(&host),
+            Ok(_) => fs::remove_file(&host),
+        };
+        r.map_err(|e| Error::Io(format!("cannot remove {}", host.display()), e))
+    }
+
+    fn make_parents(&self, host: &Path) -> Result<()> {
+        let parent = host.parent().unwrap();
+        fs::create_dir_all(parent).map_err(|e| Error::Io(format!("cannot create {}", parent.display()), e))
+    }
+
+    /// apply the changes onto the index they were made to
+    pub fn apply(&self, index: &mut Index, blockstore: &BlockStore) -> Result<()> {
+        for path in &self.whiteouts {
+            let (parent, name) = split(path);
+            if let Ok(parent) = index.lookup(blockstore, parent) {
+                try!(index.load_dir(blockstore, parent));
+                if let Some(dir) = index.i[parent as usize].dir.as_mut() {
+                    dir.remove(name);
+                }
+            }
+        }
+        try!(self.apply_dir(index, blockstore, 0, &self.path.join("files")));
+        for (path, &mode) in &self.modes {
+            if let Ok(inode) = index.lookup(blockstore, path) {
+                index.i[inode as usize].access = mode;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_dir(&self, index: &mut Index, blockstore: &BlockStore, inode: u64, host: &Path) -> Result<()> {
+        try!(index.load_dir(blockstore, inode));
+        let mut entries = try!(fs::read_dir(host).and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+                               .map_err(|e| Error::Io(format!("cannot read {}", host.display()), e)));
+        entries.sort_by_key(|e| e.file_name());
+        for e in entries {
+            let name = e.file_name().to_string_lossy().into_owned();
+            let meta = try!(e.metadata().map_err(|err| Error::Io(format!("cannot read {}", e.path().display()), err)));
+            let existing = index.i[inode as usize].dir.as_ref().and_then(|dir| dir.get(&name)).map(|e| e.i);
+            if meta.is_dir() {
+                let child = match existing {
+                    Some(i) if index.i[i as usize].kind == 1 => i,
+                    _ => index.add_upper(inode, &name, 1, e.path(), 0),
+                };
+                try!(self.apply_dir(index, blockstore, child, &e.path()));
+            } else {
+                index.add_upper(inode, &name, 2, e.path(), meta.len());
+            }
+        }
+        Ok(())
+    }
+
+    /// copy a file of the index into the upper layer before it is changed
+    pub fn copy_up(&self, index: &mut Index, blockstore: &BlockStore, inode: u64) -> Result<()> {
+        if !index.i[inode as usize].host_path.is_empty() {
+            return Ok(());
+        }
+        let path = index.path_of(inode);
+        if index.i[inode as usize].kind == 1 {
+            return Err(errno(path, EISDIR));
+        }
+        let host = self.upper(&path);
+        try!(self.make_parents(&host));
+        let mut f = try!(File::create(&host).map_err(|e| Error::Io(format!("cannot write {}", host.display()), e)));
+        try!(io::copy(&mut index.i[inode as usize].reader(blockstore), &mut f)
+             .map_err(|e| Error::Io(format!("cannot copy up {}", path), e)));
+        index.i[inode as usize].host_path = host.into_os_string();
+        Ok(())
+    }
+
+    /// a new empty file or directory with permissions mode
+    pub fn create(&mut self, index: &mut Index, blockstore: &BlockStore, parent: u64, name: &str, kind: u16, mode: u16) -> Result<u64> {
+        try!(index.load_dir(blockstore, parent));
+        let path = index.path_of(parent) + "/" + name;
+        match index.i[parent as usize].dir {
+            None => return Err(errno(path, ENOTDIR)),
+            Some(ref dir) if dir.contains_key(name) => return Err(errno(path, EEXIST)),
+            _ => {},
+        }
+        let host = self.upper(&path);
+        try!(self.remove_upper(&path));
+        try!(self.make_parents(&host));
+        let r = match kind {
+            1 => fs::create_dir(&host),
+            _ => File::create(&host).map(|_| ()),
+        };
+        try!(r.map_err(|e| Error::Io(format!("cannot create {}", host.display()), e)));
+        let inode = index.add_upper(parent, name, kind, host, 0);
+        index.i[inode as usize].access = mode;
+        self.modes.insert(path, mode);
+        try!(self.save_modes());
+        Ok(inode)
+    }
+
+    /// change the permissions of a file or directory
+    pub fn chmod(&mut self, index: &mut Index, inode: u64, mode: u16) -> Result<()> {
+        index.i[inode as usize].access = mode;
+        self.modes.insert(index.path_of(inode), mode);
+        self.save_modes()
+    }
+
+    /// unlink a file, or remove an empty directory
+    pub fn remove(&mut self, index: &mut Index, blockstore: &BlockStore, parent: u64, name: &str, dir: bool) -> Result<()> {
+        try!(index.load_dir(blockstore, parent));
+        let path = index.path_of(parent) + "/" + name;
+        let child = match index.i[parent as usize].dir.as_ref().and_then(|d| d.get(name)) {
+            None => return Err(Error::NotFound(path)),
+            Some(e) => e.i,
+        };
+        match (dir, index.i[child as usize].kind == 1) {
+            (true, false) => return Err(errno(path, ENOTDIR)),
+            (false, true) => return Err(errno(path, EISDIR)),
+            (true, true) => {
+                try!(index.load_dir(blockstore, child));
+                if index.i[child as usize].dir.as_ref().map(|d| !d.is_empty()).unwrap_or(false) {
+                    return Err(errno(path, ENOTEMPTY));
+                }
+            },
+            _ => {},
+        }
+        try!(self.remove_upper(&path));
+        index.i[parent as usize].dir.as_mut().unwrap().remove(name);
+        if self.modes.remove(&path).is_some() {
+            try!(self.save_modes());
+        }
+        self.whiteouts.insert(path);
+        self.save_whiteouts()
+    }
+
+    /// move a file, replacing a file at the new path
+    pub fn rename(&mut self, index: &mut Index, blockstore: &BlockStore,
+                  parent: u64, name: &str, newparent: u64, newname: &str) -> Result<()> {
+        try!(index.load_dir(blockstore, parent));
+        try!(index.load_dir(blockstore, newparent));
+        let path    = index.path_of(parent) + "/" + name;
+        let newpath = index.path_of(newparent) + "/" + newname;
+        let child = match index.i[parent as usize].dir.as_ref().and_then(|d| d.get(name)) {
+            None => return Err(Error::NotFound(path)),
+            Some(e) => e.clone(),
+        };
+        if child.k == 1 {
+            return Err(errno(path, EXDEV));
+        }
+        match index.i[newparent as usize].dir.as_ref().map(|d| d.get(newname)) {
+            None => return Err(errno(newpath, ENOTDIR)),
+            Some(Some(e)) if e.k == 1 => return Err(errno(newpath, EISDIR)),
+            _ => {},
+        }
+
+        try!(self.copy_up(index, blockstore, child.i));
+        let host = self.upper(&newpath);
+        try!(self.remove_upper(&newpath));
+        try!(self.make_parents(&host));
+        try!(fs::rename(&index.i[child.i as usize].host_path, &host)
+             .map_err(|e| Error::Io(format!("cannot move {} to {}", path, newpath), e)));
+
+        index.i[child.i as usize].host_path = host.into_os_string();
+        index.i[child.i as usize].parent    = newparent;
+        index.i[parent as usize].dir.as_mut().unwrap().remove(name);
+        index.i[newparent as usize].dir.as_mut().unwrap().insert(newname.to_owned(), child);
+        match self.modes.remove(&path) {
+            Some(mode) => self.modes.insert(newpath, mode),
+            None => self.modes.remove(&newpath),
+        };
+        try!(self.save_modes());
+        self.whiteouts.insert(path);
+        self.save_whiteouts()
+    }
+
+    /// change the size of a file
+    pub fn truncate(&self, index: &mut Index, blockstore: &BlockStore, inode: u64, size: u64) -> Result<()> {
+        try!(self.copy_up(index, blockstore, inode));
+        let host = index.i[inode as usize].host_path.clone();
+        try!(OpenOptions::new().write(true).open(&host).and_then(|f| f.set_len(size))
+             .map_err(|e| Error::Io(format!("cannot truncate {:?}", host), e)));
+        index.i[inode as usize].size = size;
+        Ok(())
+    }
+}
+
+impl Index {
+    /// an inode for a file or directory of the upper layer, replacing any entry of that name
+    fn add_upper(&mut self, parent: u64, name: &str, kind: u16, host_path: PathBuf, size: u64) -> u64 {
+        let i = self.i.len() as u64;
+        self.i.push(Inode{
+            inode:  i,
+            parent: parent,
+            size:   size,
+            kind:   kind,
+            access: 0o775,
+
+            dir:     if kind == 1 { Some(HashMap::new()) } else { None },
+            hash:    None,
+            content: if kind == 1 { None } else { Some(Vec::new()) },
+            inline:  None,
+
+            host: HostMeta::default(),
+
+            host_path: host_path.into_os_string(),
+            tree:      None,
+        });
+        self.i[parent as usize].dir.get_or_insert(HashMap::new()).insert(name.to_owned(), ContentDirEntry{
+            i: i,
+            k: kind,
+        });
+        i
+    }
+
+    /// the path of an inode like /a/b. the root is the empty path
+    pub fn path_of(&self, inode: u64) -> String {
+        let mut names = Vec::new();
+        let mut at = inode;
+        while at != 0 {
+            let parent = self.i[at as usize].parent;
+            let name = self.i[parent as usize].dir.as_ref()
+                .and_then(|dir| dir.iter().find(|&(_, e)| e.i == at))
+                .map(|(name, _)| name.clone()).unwrap_or(String::new());
+            names.push(name);
+            at = parent;
+        }
+        names.into_iter().rev().fold(String::new(), |acc, name| acc + "/" + &name)
+    }
+
+    /// drop inodes that are no longer in any directory, numbering the rest breadth first,
+    /// so parents still come before their subdirectories
+    pub fn prune(&mut self) {
+        let mut order = Vec::new();
+        let mut todo  = VecDeque::new();
+        todo.push_back(0 as u64);
+        while let Some(inode) = todo.pop_front() {
+            order.push(inode);
+            if let Some(ref dir) = self.i[inode as usize].dir {
+                let mut names: Vec<&String> = dir.keys().collect();
+                names.sort();
+                todo.extend(names.into_iter().map(|name| dir[name].i));
+            }
+        }
+
+        let renumber: HashMap<u64, u64> = order.iter().enumerate().map(|(new, old)| (*old, new as u64)).collect();
+        let old = ::std::mem::replace(&mut self.i, Vec::new());
+        for inode in order {
+            let mut i = old[inode as usize].clone();
+            i.inode  = renumber[&inode];
+            i.parent = renumber[&i.parent];
+            if let Some(ref mut dir) = i.dir {
+                for e in dir.values_mut() {
+                    e.i = renumber[&e.i];
+                }
+            }
+            self.i.push(i);
+        }
+    }
+}
+
+#[test]
+fn changes_apply_onto_the_base() {
+    let dir = ::testing::temp_dir("overlay");
+    fs::create_dir_all(dir.join("base/a")).unwrap();
+    File::create(dir.join("base/a/x")).unwrap().write_all(b"x").unwrap();
+    File::create(dir.join("base/y")).unwrap().write_all(b"y").unwrap();
+
+    let mut bs = ::blockstore::in_memory();
+    let mut base = ::index::from_host(dir.join("base").into_os_string()).unwrap();
+    base.store_inodes(&mut bs, None, true, &::testing::options()).unwrap();
+    let root = base.store_tree(&mut bs).unwrap().c.unwrap()[0].h.clone();
+
+    let mut index = Index::from_root(&bs, &root).unwrap();
+    let mut overlay = open(&dir.join("upper"), "base").unwrap();
+    let a = index.lookup(&bs, "/a").unwrap();
+    let z = overlay.create(&mut index, &bs, a, "z", 2, 0o640).unwrap();
+    fs::OpenOptions::new().write(true).open(&index.i[z as usize].host_path).unwrap().write_all(b"zz").unwrap();
+    overlay.rename(&mut index, &bs, 0, "y", a, "y").unwrap();
+    overlay.chmod(&mut index, a, 0o700).unwrap();
+    overlay.remove(&mut index, &bs, a, "x", false).unwrap();
+    assert!(overlay.rename(&mut index, &bs, 0, "a", 0, "b").is_err());
+
+    // a remount sees the same tree
+    let mut again = Index::from_root(&bs, &root).unwrap();
+    load(&dir.join("upper")).unwrap().apply(&mut again, &bs).unwrap();
+    again.load_all(&bs).unwrap();
+    again.prune();
+    let mut paths: Vec<String> = again.paths().into_iter().map(|(path, _)| path).collect();
+    paths.sort();
+    assert_eq!(paths, vec!["/a", "/a/y", "/a/z"]);
+    let mut modes = Vec::new();
+    for path in &paths {
+        let inode = again.lookup(&bs, path).unwrap();
+        modes.push(again.i[inode as usize].access);
+    }
+    assert_eq!(modes, vec![0o700, 0o775, 0o640]);
+
+    let mut content = Vec::new();
+    let y = again.lookup(&bs, "/a/y").unwrap();
+    File::open(&again.i[y as usize].host_path).unwrap().read_to_end(&mut content).unwrap();
+    assert_eq!(content, b"y");
+}
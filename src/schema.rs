@@ -0,0 +1,122 @@
+use error::{Error, Result};
+use index::{Index, Inode, HostMeta, ContentBlockEntry, ContentDirEntry};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use std::collections::HashMap;
+use tree;
+
+// versions of a serialized index.
+//
+//  1  inodes, or the blocks of another index of version 1. they have no inline content
+//  2  a tree of directory nodes, see tree.rs
+//
+// msgpack structs are arrays, and rmp-serde doesn't reject elements left over after the last field,
+// so the version is read first and the rest is parsed only as what that version wrote.
+
+/// the version every index is written as
+pub const CURRENT: u16 = tree::VERSION;
+
+fn parse<T: DeserializeOwned>(buf: &[u8]) -> Result<T> {
+    T::deserialize(&mut ::rmps::Deserializer::new(buf)).map_err(|e| Error::CorruptIndex(e.to_string()))
+}
+
+/// parse an index of any supported version
+pub fn decode(buf: &[u8]) -> Result<Index> {
+    let (v, _, _): (u16, IgnoredAny, IgnoredAny) = try!(parse(buf));
+    match v {
+        1 => decode_v1(buf),
+        CURRENT => parse(buf),
+        v => Err(Error::BadIndexVersion(v)),
+    }
+}
+
+#[derive(Deserialize)]
+struct IndexV1 {
+    v: u16,
+    i: Vec<InodeV1>,
+    c: Option<Vec<ContentBlockEntry>>,
+}
+
+#[derive(Deserialize)]
+struct InodeV1 {
+    inode:  u64,
+    parent: u64,
+    size:   u64,
+    kind:   u16,
+    access: u16,
+
+    dir:     Option<HashMap<String, ContentDirEntry>>,
+    hash:    Option<String>,
+    content: Option<Vec<ContentBlockEntry>>,
+}
+
+fn decode_v1(buf: &[u8]) -> Result<Index> {
+    let hi = try!(parse::<IndexV1>(buf));
+    Ok(Index {
+        v: hi.v,
+        i: hi.i.into_iter().map(|i| Inode {
+            inode:   i.inode,
+            parent:  i.parent,
+            size:    i.size,
+            kind:    i.kind,
+            access:  i.access,
+            dir:     i.dir,
+            hash:    i.hash,
+            content: i.content,
+            inline:  None,
+
+            host: HostMeta::default(),
+
+            host_path: ::std::ffi::OsString::new(),
+            tree:      None,
+        }).collect(),
+        c: hi.c,
+    })
+}
+
+#[cfg(test)]
+#[derive(Serialize)]
+struct OldInode {
+    inode:  u64,
+    parent: u64,
+    size:   u64,
+    kind:   u16,
+    access: u16,
+    dir:     Option<::std::collections::BTreeMap<String, ContentDirEntry>>,
+    hash:    Option<String>,
+    content: Option<Vec<ContentBlockEntry>>,
+}
+
+#[test]
+fn reads_every_version() {
+    use serde::Serialize;
+    let encode = |v: u16, i: Vec<OldInode>| {
+        let mut buf = Vec::new();
+        (v, i, None as Option<Vec<ContentBlockEntry>>).serialize(&mut ::rmps::Serializer::new(&mut buf)).unwrap();
+        buf
+    };
+    let mut dir = ::std::collections::BTreeMap::new();
+    dir.insert("a".to_owned(), ContentDirEntry{i: 1, k: 2});
+    let old = vec![
+        OldInode{inode: 0, parent: 0, size: 0, kind: 1, access: 0o775, dir: Some(dir), hash: None, content: None},
+        OldInode{inode: 1, parent: 0, size: 5, kind: 2, access: 0o775, dir: None, hash: None, content: Some(vec![
+            ContentBlockEntry{h: vec![1; 32], o: 0, l: 5},
+        ])},
+    ];
+
+    let hi = decode(&encode(1, old)).unwrap();
+    assert_eq!(hi.i.len(), 2);
+    assert_eq!(hi.i[1].size, 5);
+    assert!(hi.i[1].inline.is_none());
+    assert_eq!(hi.paths()["/a"], 1);
+
+    let mut current = Vec::new();
+    (CURRENT, Vec::<Inode>::new(), Some(vec![ContentBlockEntry{h: vec![2; 32], o: 0, l: 7}]))
+        .serialize(&mut ::rmps::Serializer::new(&mut current)).unwrap();
+    let hi = decode(&current).unwrap();
+    assert_eq!((hi.v, hi.i.len(), hi.c.unwrap()[0].l), (CURRENT, 0, 7));
+
+    match decode(&encode(CURRENT + 1, Vec::new())) {
+        Err(Error::BadIndexVersion(v)) => assert_eq!(v, CURRENT + 1),
+        _ => panic!("expected a bad version"),
+    }
+}
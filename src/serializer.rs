@@ -1,16 +1,22 @@
-use blockstore::{Block, BlockStore, BlockShard};
+use blockstore::{BlockStore, BlockShard, PendingBlock};
 use chunker::*;
+use error::Error;
+use hash::{self, HashAlgo};
 use index::*;
 use pbr::ProgressBar;
-use readchain::{Take,Chain};
+use splitter::{self, Splitter};
 use serde::{Serialize, Deserialize};
+use std::cell::RefCell;
+use std::collections::{HashSet, BTreeMap};
 use std::ffi::OsString;
-use std::io::{Stdout, Seek, SeekFrom, BufReader};
+use std::io::{self, Stdout, Seek, SeekFrom, BufReader};
 use std::path::Path;
+use std::rc::Rc;
 use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use nix::errno::Errno;
+use nix::unistd::{lseek64, Whence};
 
-use elfkit;
-use sha2::{Sha256, Digest};
 use std::io::Read;
 
 macro_rules! kb_fmt {
@@ -26,8 +32,68 @@ macro_rules! kb_fmt {
     }}
 }
 
+/// how store_inodes chunks host files. whether blocks may span files is a setting of the store
+pub struct StoreOptions {
+    pub jobs:     usize, //threads scanning files for block boundaries
+    pub inline:   u64,   //files smaller than this are stored in the index instead of blocks
+    pub splitters: Vec<Box<Splitter>>, //content aware boundaries, tried in order
+}
+
+/// what storing a tree would produce, without storing anything
+pub struct ChunkStats {
+    pub blocks:        usize,
+    pub unique_blocks: usize,
+    pub unique_bytes:  usize,
+    pub new_blocks:    usize, //unique blocks not yet in the store
+    pub new_bytes:     usize,
+}
+
+impl ChunkStats {
+    pub fn print_header() {
+        println!("{:10} {:>10} {:>10} {:>12} {:>10} {:>12}",
+                 "mode", "blocks", "unique", "unique size", "new", "new size");
+    }
+
+    pub fn print(&self, mode: &str) {
+        let unique_bytes = self.unique_bytes;
+        let new_bytes    = self.new_bytes;
+        println!("{:10} {:>10} {:>10} {:>12} {:>10} {:>12}",
+                 mode, self.blocks, self.unique_blocks, kb_fmt!(unique_bytes),
+                 self.new_blocks, kb_fmt!(new_bytes));
+    }
+}
+
+/// a host file that is opened on its first read, so only the one being chunked is open.
+/// errors name the file, since the chunker doesn't know it
+struct HostFile {
+    path: OsString,
+    f:    Option<BufReader<File>>,
+}
+
+impl Read for HostFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let path = &self.path;
+        let named = |e: io::Error| io::Error::new(e.kind(), format!("{:?}: {}", path, e));
+        if self.f.is_none() {
+            self.f = Some(BufReader::new(try!(File::open(path).map_err(&named))));
+        }
+        self.f.as_mut().unwrap().read(buf).map_err(&named)
+    }
+}
+
+/// per_file never lets a block span files, which unchanged subtrees need to share their nodes
+fn chunk_files<'a>(files: Vec<&'a Inode>, per_file: bool, opts: &StoreOptions, algo: HashAlgo) -> Box<Iterator<Item=Result<Chunk<u64>, Error>> + 'a> {
+    if opts.jobs > 1 {
+        let files = files.iter().map(|i| (i.host_path.clone(), i.inode)).collect();
+        Box::new(ParallelChunker::new(files, 9, per_file, opts.jobs, algo))
+    } else {
+        let it = files.into_iter().map(|i| (HostFile{path: i.host_path.clone(), f: None}, i.inode));
+        Box::new(Chunker::new(Box::new(it), ::rollsum::Bup::new(), 9, per_file, algo))
+    }
+}
+
 impl Index {
-    pub fn store_inodes(&mut self, blockstore: &mut BlockStore) {
+    pub fn store_inodes(&mut self, blockstore: &mut BlockStore, parent: Option<&Index>, per_file: bool, opts: &StoreOptions) -> Result<(), Error> {
 
         let total_bytes = self.i.iter().fold(0, |acc, ref x| acc + x.size);
 
@@ -38,194 +104,359 @@ impl Index {
         let mut new_blocks = 0;
         let mut total_blocks = 0;
 
+        // files that did not change since the parent index keep their content entries
+        let mut done = HashSet::new();
 
-        let mut inodes = self.i.to_vec();
+        // inodes that were loaded from an index rather than the host are stored already
+        for i in &self.i {
+            if i.kind != 1 && i.host_path.is_empty() {
+                done.insert(i.inode);
+                bar.add(i.size);
+            }
+        }
+        if let Some(parent) = parent {
+            let mut reused_bytes = 0;
+            let parent_paths = parent.paths();
+            for (path, inode) in self.paths() {
+                let pi = match parent_paths.get(&path).and_then(|pi| parent.i.get(*pi as usize)) {
+                    None => continue,
+                    Some(pi) => pi,
+                };
+                let i = &mut self.i[inode as usize];
+                if i.kind != 2 || (pi.kind != 2 && pi.kind != 3) ||
+                    pi.size != i.size || pi.host != i.host {
+                    continue;
+                }
+                let content = match pi.content {
+                    None => continue,
+                    Some(ref content) => content,
+                };
+                if !content.iter().all(|c| c.is_hole() || blockstore.get(&c.h).is_some()) {
+                    continue;
+                }
+                i.kind    = pi.kind;
+                i.content = Some(content.iter().map(|c| ContentBlockEntry{
+                    h: if c.is_hole() { Vec::new() } else { hash::normalize(&c.h) },
+                    o: c.o,
+                    l: c.l,
+                }).collect());
+                i.inline  = pi.inline.clone();
+                done.insert(inode);
+                reused_bytes += i.size;
+            }
+            bar.add(reused_bytes);
+            println!("reusing {} unchanged files ({}) from parent index", done.len(), kb_fmt!(reused_bytes));
+        }
 
-        // detect special files
-        for i in &mut inodes {
-            if i.kind != 2 {
+        // tiny files are stored inline in the index
+        let mut inlined = 0;
+        for i in &mut self.i {
+            if i.kind != 2 || i.size >= opts.inline || done.contains(&i.inode) {
                 continue;
             }
-            let mut host_file  = File::open(&i.host_path).unwrap();
-            let cuts = match elfkit::Elf::from_reader(&mut host_file) {
-                Err(_) => None,
-                Ok(mut elf) => {
-                    let mut r = None;
-                    for sec in elf.sections.drain(..) {
-                        if sec.header.shtype == elfkit::types::SectionType(0x6fffff01) {
-                            let mut rr = Vec::new();
-                            let mut io = &sec.content.into_raw().unwrap()[..];
-                            while let Ok(o) = elf_read_u32!(&elf.header, io) {
-                                rr.push(o as usize);
-                            }
-                            r = Some(rr);
-                        }
-                    }
-                    r
+            let mut buf = Vec::new();
+            try!(File::open(&i.host_path).and_then(|mut f| f.read_to_end(&mut buf))
+                 .map_err(|e| Error::Io(format!("cannot read {:?}", i.host_path), e)));
+            bar.add(buf.len() as u64);
+            i.size   = buf.len() as u64;
+            i.inline = Some(buf);
+            done.insert(i.inode);
+            inlined += 1;
+        }
+
+        // sparse files only store their data extents, holes are recorded as such
+        let mut sparse = 0;
+        for inode in 0..self.i.len() {
+            let (host_path, size) = {
+                let i = &self.i[inode];
+                if i.kind != 2 || i.size == 0 || done.contains(&i.inode) {
+                    continue;
                 }
+                (i.host_path.clone(), i.size)
             };
-            match cuts {
-                None => {},
-                Some(mut cuts) => {
-                    i.kind = 3;
-                    let mut at = 0;
-                    cuts.push(host_file.metadata().unwrap().len() as usize);
-                    cuts.sort_unstable();
-                    host_file.seek(SeekFrom::Start(0));
-                    for cut in cuts {
-                        let mut buf = vec![0;cut - at];
-                        host_file.read_exact(&mut buf).unwrap();
-                        let hash = Sha256::digest(&buf).as_slice().to_vec();
-                        if blockstore.insert(hash.clone(), Block {
-                            shards: vec![BlockShard{
-                                file:    i.host_path.clone(),
-                                offset:  at,
-                                size:    buf.len(),
-                            }],
-                            size: buf.len(),
-                        }) {
-                            new_blocks +=1;
-                            new_bytes  += buf.len();
-                        }
-                        total_blocks += 1;
-                        bar.add(buf.len() as u64);
-
-                        if let None = self.i[i.inode as usize].content {
-                            self.i[i.inode as usize].content = Some(Vec::new());
-                        }
-                        self.i[i.inode as usize].content.as_mut().unwrap().push(ContentBlockEntry{
-                            h: hash,
-                            o: 0,
-                            l: buf.len() as u64,
-                        });
-
-                        at = cut;
-                    }
-                    let mut buf = Vec::new();
-                    assert!(host_file.read_to_end(&mut buf).unwrap() == 0);
-
+            let cannot_read = |e| Error::Io(format!("cannot read {:?}", host_path), e);
+            let extents = data_extents(&try!(File::open(&host_path).map_err(&cannot_read)), size);
+            if extents.len() == 1 && extents[0] == (0, size) {
+                continue;
+            }
 
+            let mut at = 0;
+            for (start, len) in extents {
+                if start > at {
+                    self.i[inode].content.as_mut().unwrap().push(ContentBlockEntry::hole(start - at));
+                }
+                let mut f = try!(File::open(&host_path).map_err(&cannot_read));
+                try!(f.seek(SeekFrom::Start(start)).map_err(&cannot_read));
+                let it = vec![(BufReader::new(f).take(len), inode as u64)].into_iter();
+                for c in Chunker::new(Box::new(it), ::rollsum::Bup::new(), 9, false, blockstore.hash) {
+                    let c = try!(c);
+                    bar.add(c.len as u64);
+                    let len = c.len;
+                    if try!(self.insert_chunk(blockstore, c, start as usize)) {
+                        new_blocks +=1;
+                        new_bytes  += len;
+                    }
+                    total_blocks += 1;
                 }
+                at = start + len;
             }
+            if size > at {
+                self.i[inode].content.as_mut().unwrap().push(ContentBlockEntry::hole(size - at));
+            }
+            bar.add(size - extents_len(&self.i[inode]));
+            done.insert(inode as u64);
+            sparse += 1;
         }
 
+        // content aware splitters cut files at format specific boundaries
+        let mut split = 0;
+        for inode in 0..self.i.len() {
+            let host_path = {
+                let i = &self.i[inode];
+                if i.kind != 2 || done.contains(&i.inode) {
+                    continue;
+                }
+                i.host_path.clone()
+            };
+            let cannot_read = |e| Error::Io(format!("cannot read {:?}", host_path), e);
+            let mut host_file = try!(File::open(&host_path).map_err(&cannot_read));
+            let mut cuts = match try!(splitter::split(&opts.splitters, &mut host_file).map_err(&cannot_read)) {
+                None => continue,
+                Some((_, Ok(cuts))) => cuts,
+                Some((name, Err(e))) => {
+                    println!("warning: ignoring {} of {}: {}", name, host_path.to_string_lossy(), e);
+                    continue;
+                },
+            };
+            self.i[inode].kind = 3;
+            cuts.push(try!(host_file.metadata().map_err(&cannot_read)).len() as usize);
+
+            // blocks end at every cut, and are content defined in between
+            let mut at = 0;
+            for cut in cuts {
+                try!(host_file.seek(SeekFrom::Start(at as u64)).map_err(&cannot_read));
+                let it = vec![(BufReader::new(&host_file).take((cut - at) as u64), inode as u64)].into_iter();
+                for c in Chunker::new(Box::new(it), ::rollsum::Bup::new(), 9, false, blockstore.hash) {
+                    let c = try!(c);
+                    bar.add(c.len as u64);
+                    let len = c.len;
+                    if try!(self.insert_chunk(blockstore, c, at)) {
+                        new_blocks +=1;
+                        new_bytes  += len;
+                    }
+                    total_blocks += 1;
+                }
+                at = cut;
+            }
+            done.insert(inode as u64);
+            split += 1;
+        }
 
-
-        let it = inodes.iter().filter(|i|i.kind == 2).map(|i| {
-            (BufReader::new(File::open(&i.host_path).unwrap()), i.inode)
-        });
-
-        let mut ci = Chunker::new(Box::new(it), ::rollsum::Bup::new(), 9);
-        while let Some(c) = ci.next() {
+        let inodes = self.i.to_vec();
+        let files = inodes.iter().filter(|i|i.kind == 2 && !done.contains(&i.inode)).collect();
+        for c in chunk_files(files, per_file, opts, blockstore.hash) {
+            let c = try!(c);
             bar.add((c.len) as u64);
+            print_progress_bar(&mut bar, &self.i[c.parts.last().unwrap().i as usize].host_path);
 
-            let mut block_shards = Vec::new();
-            //println!("block {}", c.hash.to_hex());
-            for ibr in c.parts {
-                //println!("   inode {} at offset {} is {} into the block with size {}",
-                //         ibr.i, ibr.file_start, ibr.block_start, ibr.file_end - ibr.file_start);
-
-                block_shards.push(BlockShard{
-                    file:    self.i[ibr.i as usize].host_path.clone(),
-                    offset:  ibr.file_start,
-                    size:    ibr.file_end - ibr.file_start,
-                });
-
-                if let None = self.i[ibr.i as usize].content {
-                    self.i[ibr.i as usize].content = Some(Vec::new());
-                }
-                self.i[ibr.i as usize].content.as_mut().unwrap().push(ContentBlockEntry{
-                    h: c.hash.clone(),
-                    o: ibr.block_start as u64,
-                    l: (ibr.file_end - ibr.file_start) as u64,
-                });
-                print_progress_bar(&mut bar, &self.i[ibr.i as usize].host_path);
-            }
-            if blockstore.insert(c.hash, Block{
-                shards: block_shards,
-                size: c.len,
-            }) {
+            let len = c.len;
+            if try!(self.insert_chunk(blockstore, c, 0)) {
                 new_blocks +=1;
-                new_bytes  += c.len;
+                new_bytes  += len;
             }
             total_blocks += 1;
         }
 
         bar.finish();
-        println!("done indexing {} inodes to {} blocks", self.i.len(), total_blocks);
+        println!("done indexing {} inodes to {} blocks ({} inline, {} sparse, {} split)",
+                 self.i.len(), total_blocks, inlined, sparse, split);
         println!(" + {} blocks {}", new_blocks, kb_fmt!(new_bytes));
+        Ok(())
     }
 
 
-    pub fn store_index(&mut self, blockstore: &mut BlockStore) -> Index {
-        //TODO used a namedtempfile isnt great,
-        //but i can't be bothered to figure out passing a &File to BlockShard right now
-        let mut tmpindex = ::tempfile::NamedTempFile::new_in(".").unwrap();
-        self.serialize(&mut ::rmps::Serializer::new(&mut tmpindex)).unwrap();
-        tmpindex.seek(SeekFrom::Start(0)).unwrap();
-        let path = OsString::from(tmpindex.path().to_str().unwrap());
+    /// record a chunk of host files in the content of its inodes and insert it into the store.
+    /// offset is where in the host files the chunked stream started
+    fn insert_chunk(&mut self, blockstore: &mut BlockStore, c: Chunk<u64>, offset: usize) -> Result<bool, Error> {
+        let mut block_shards = Vec::new();
+        for ibr in c.parts {
+            block_shards.push(BlockShard{
+                file:    self.i[ibr.i as usize].host_path.clone(),
+                offset:  offset + ibr.file_start,
+                size:    ibr.file_end - ibr.file_start,
+            });
+
+            if let None = self.i[ibr.i as usize].content {
+                self.i[ibr.i as usize].content = Some(Vec::new());
+            }
+            self.i[ibr.i as usize].content.as_mut().unwrap().push(ContentBlockEntry{
+                h: c.hash.clone(),
+                o: ibr.block_start as u64,
+                l: (ibr.file_end - ibr.file_start) as u64,
+            });
+        }
+        blockstore.insert(c.hash, PendingBlock{
+            shards: block_shards,
+            size: c.len,
+        })
+    }
+
+    /// chunk all files like store_inodes would, to compare dedup between chunking modes
+    pub fn chunk_stats(&self, blockstore: &BlockStore, per_file: bool, opts: &StoreOptions) -> Result<ChunkStats, Error> {
+        let mut stats = ChunkStats {
+            blocks:        0,
+            unique_blocks: 0,
+            unique_bytes:  0,
+            new_blocks:    0,
+            new_bytes:     0,
+        };
+        let mut seen = HashSet::new();
+        let files = self.i.iter().filter(|i|i.kind == 2 && i.size >= opts.inline).collect();
+        for c in chunk_files(files, per_file, opts, blockstore.hash) {
+            let c = try!(c);
+            stats.blocks += 1;
+            if !seen.insert(c.hash.clone()) {
+                continue;
+            }
+            stats.unique_blocks += 1;
+            stats.unique_bytes  += c.len;
+            if blockstore.get(&c.hash).is_none() {
+                stats.new_blocks += 1;
+                stats.new_bytes  += c.len;
+            }
+        }
+        Ok(stats)
+    }
 
-        let tv= vec![tmpindex];
-        let it = tv.iter().map(|i|(i,0));
-        let mut ci = Chunker::new(Box::new(it), ::rollsum::Bup::new(), 12);
+    /// store the content of the single file of an index created by from_stream.
+    /// blocks are inserted from memory, since there is no host file to refer to later
+    pub fn store_stream<R: Read>(&mut self, blockstore: &mut BlockStore, r: R) -> Result<(), Error> {
+        let recorded = Rc::new(RefCell::new(Vec::new()));
+        let recorder = Recorder {
+            inner:    r,
+            recorded: recorded.clone(),
+        };
 
-        let mut total_blocks = 0;
+        let mut new_bytes  = 0;
         let mut new_blocks = 0;
+        let mut total_blocks = 0;
+        let mut total_bytes  = 0;
 
-        let mut cbrs = Vec::new();
+        let it = vec![(recorder, 1)].into_iter();
+        let mut ci = Chunker::new(Box::new(it), ::rollsum::Bup::new(), 9, false, blockstore.hash);
         while let Some(c) = ci.next() {
-            let mut block_shards = Vec::new();
-            //println!("block {}", c.hash.to_hex());
+            let c = try!(c);
+            let content : Vec<u8> = recorded.borrow_mut().drain(..c.len).collect();
             for ibr in c.parts {
-                //println!("   inode {} at offset {} is {} into the block with size {}",
-                //         ibr.i, ibr.file_start, ibr.block_start, ibr.file_end - ibr.file_start);
-                block_shards.push(BlockShard{
-                    file:    path.clone(),
-                    offset:  ibr.file_start,
-                    size:    ibr.file_end - ibr.file_start,
-                });
-                cbrs.push(ContentBlockEntry{
+                self.i[ibr.i as usize].content.as_mut().unwrap().push(ContentBlockEntry{
                     h: c.hash.clone(),
                     o: ibr.block_start as u64,
                     l: (ibr.file_end - ibr.file_start) as u64,
                 });
             }
-            if blockstore.insert(c.hash, Block{
-                shards: block_shards,
-                size: c.len,
-            }) {
-                new_blocks += 1;
+            if try!(blockstore.insert_bytes(c.hash, &content)) {
+                new_blocks +=1;
+                new_bytes  += c.len;
             }
             total_blocks += 1;
+            total_bytes  += c.len;
         }
-        println!("done serializing index to {} blocks ({} new)", total_blocks, new_blocks);
-        Index{
-            v: 1,
-            i: Vec::new(),
-            c: Some(cbrs),
-        }
+        self.i[1].size = total_bytes as u64;
+
+        println!("done indexing {} to {} blocks", kb_fmt!(total_bytes), total_blocks);
+        println!(" + {} blocks {}", new_blocks, kb_fmt!(new_bytes));
+        Ok(())
+    }
+
+    /// the next index in a chain of v1 indices
+    pub fn load_index(&self, blockstore: &BlockStore) -> Result<Index, Error> {
+        let buf = try!(::tree::read_bytes(blockstore, self.c.as_ref().map(|c| &c[..]).unwrap_or(&[])));
+        ::schema::decode(&buf)
+    }
+
+    pub fn save_to_file(&mut self, path: &Path) -> Result<(), Error> {
+        let mut f = try!(File::create(path).map_err(|e| Error::Io(format!("cannot write {}", path.display()), e)));
+        self.serialize(&mut ::rmps::Serializer::new(&mut f)).map_err(|e| Error::Io(format!("cannot write {}", path.display()),
+                                                                                   ::std::io::Error::new(::std::io::ErrorKind::Other, e)))
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<Index, Error> {
+        Index::decode_file(path, &try!(Index::read_file(path)))
     }
 
-    pub fn load_index(&self, blockstore: &BlockStore) -> Index {
-        let it = self.c.as_ref().unwrap().iter().map(|c| {
-            let block = blockstore.get(&c.h).expect("block not found");
-            let mut re = block.chain();
-            re.seek(SeekFrom::Current(c.o as i64)).unwrap();
-            Take::limit(re, c.l as usize)
-        });
-        let mut f = Chain::new(Box::new(it));
-        Index::deserialize(&mut ::rmps::Deserializer::new(&mut f)).unwrap()
+    /// the serialized index in the file at path, which is what a signature covers
+    pub fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_end(&mut buf)).map_err(|e| match e.kind() {
+            ::std::io::ErrorKind::NotFound => Error::NotFound(format!("index {}", path.display())),
+            _ => Error::Io(format!("cannot read {}", path.display()), e),
+        }));
+        Ok(buf)
     }
 
-    pub fn save_to_file(&mut self, path: &Path) {
-        let mut f = File::create(path).unwrap();
-        self.serialize(&mut ::rmps::Serializer::new(&mut f)).unwrap();
+    /// parse an index read from the file at path
+    pub fn decode_file(path: &Path, buf: &[u8]) -> Result<Index, Error> {
+        ::schema::decode(buf).map_err(|e| match e {
+            Error::CorruptIndex(e) => Error::CorruptIndex(format!("{}: {}", path.display(), e)),
+            e => e,
+        })
     }
 
-    pub fn load_from_file(path: &Path) -> Index {
-        let mut f = File::open(path).unwrap();
-        Index::deserialize(&mut ::rmps::Deserializer::new(&mut f)).unwrap()
+    /// keep the host metadata store_inodes compares a parent against, which the index doesn't hold.
+    /// it describes the build host, not the image, so it lives next to the named index
+    pub fn save_host_meta(&self, path: &Path) -> Result<(), Error> {
+        let meta: BTreeMap<String, &HostMeta> = self.paths().into_iter().map(|(path, i)| {
+            (path, &self.i[i as usize].host)
+        }).collect();
+        let mut f = try!(File::create(path).map_err(|e| Error::Io(format!("cannot write {}", path.display()), e)));
+        meta.serialize(&mut ::rmps::Serializer::new(&mut f)).map_err(|e| Error::Io(format!("cannot write {}", path.display()),
+                                                                                   ::std::io::Error::new(::std::io::ErrorKind::Other, e)))
+    }
+
+    /// restore host metadata saved by save_host_meta onto a loaded index, if there is any
+    pub fn load_host_meta(&mut self, path: &Path) {
+        let mut f = match File::open(path) {
+            Err(_) => return,
+            Ok(f) => f,
+        };
+        // only a hint for reusing content, a broken one just means reading every file again
+        let meta = match BTreeMap::<String, HostMeta>::deserialize(&mut ::rmps::Deserializer::new(&mut f)) {
+            Err(_) => return,
+            Ok(meta) => meta,
+        };
+        for (path, i) in self.paths() {
+            if let Some(host) = meta.get(&path) {
+                self.i[i as usize].host = host.clone();
+            }
+        }
+    }
+}
+
+/// data extents of a host file as (offset, length), skipping holes.
+/// a file without holes, or on a filesystem that can't tell, has a single extent
+fn data_extents(f: &File, size: u64) -> Vec<(u64, u64)> {
+    let fd = f.as_raw_fd();
+    let mut r = Vec::new();
+    let mut at = 0;
+    while at < size {
+        let data = match lseek64(fd, at as i64, Whence::SeekData) {
+            Ok(data) => data as u64,
+            Err(::nix::Error::Sys(Errno::ENXIO)) => break, //only a hole left
+            Err(_) => return vec![(0, size)],
+        };
+        let hole = match lseek64(fd, data as i64, Whence::SeekHole) {
+            Ok(hole) => ::std::cmp::min(hole as u64, size),
+            Err(_) => return vec![(0, size)],
+        };
+        r.push((data, hole - data));
+        at = hole;
     }
+    r
+}
+
+/// bytes of an inode that are covered by content blocks rather than holes
+fn extents_len(i: &Inode) -> u64 {
+    i.content.as_ref().unwrap().iter().filter(|c| !c.is_hole()).fold(0, |acc, c| acc + c.l)
 }
 
 fn print_progress_bar(bar: &mut ProgressBar<Stdout>, path: &OsString){
@@ -237,4 +468,128 @@ fn print_progress_bar(bar: &mut ProgressBar<Stdout>, path: &OsString){
     }
 }
 
+/// keeps everything read from the inner reader,
+/// so chunks can be inserted after the Chunker consumed them
+struct Recorder<R> where R: Read {
+    inner:    R,
+    recorded: Rc<RefCell<Vec<u8>>>,
+}
+
+impl<R> Read for Recorder<R> where R: Read {
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        let rs = self.inner.read(buf)?;
+        self.recorded.borrow_mut().extend_from_slice(&buf[..rs]);
+        Ok(rs)
+    }
+}
+
+
+#[cfg(test)]
+struct FixedCuts(Vec<usize>);
+
+#[cfg(test)]
+impl Splitter for FixedCuts {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+    fn cuts(&self, _f: &mut File) -> Option<Result<Vec<usize>, String>> {
+        Some(Ok(self.0.clone()))
+    }
+}
+
+#[cfg(test)]
+fn store_with_cuts(name: &str, content: &[u8], cuts: Vec<usize>) -> (Index, BlockStore) {
+    use std::io::Write;
+    let dir = ::testing::temp_dir(&format!("serializer-{}", name));
+    File::create(dir.join("a")).unwrap().write_all(content).unwrap();
+
+    let mut index = from_host(dir.as_os_str().to_owned()).unwrap();
+    let mut bs = ::blockstore::in_memory();
+    index.store_inodes(&mut bs, None, false, &StoreOptions {
+        splitters: vec![Box::new(FixedCuts(cuts))],
+        ..::testing::options()
+    }).unwrap();
+    (index, bs)
+}
+
+#[cfg(test)]
+fn assert_content(index: &Index, bs: &BlockStore, content: &[u8]) {
+    let mut stored = Vec::new();
+    index.i[1].reader(bs).read_to_end(&mut stored).unwrap();
+    assert!(stored == content);
+    let entries = index.i[1].content.as_ref().unwrap();
+    assert_eq!(entries.iter().fold(0, |acc, c| acc + c.l), content.len() as u64);
+}
+
+#[test]
+fn cut_file_is_stored_once() {
+    let content: Vec<u8> = (0..10000).map(|i| (i * 7 % 251) as u8).collect();
+    let (index, bs) = store_with_cuts("once", &content, vec![3000, 1000, 3000, 0, 10000]);
+    assert_eq!(index.i[1].kind, 3);
+    assert_content(&index, &bs, &content);
+
+    // every cut ends a block
+    let mut ends = Vec::new();
+    for c in index.i[1].content.as_ref().unwrap() {
+        let at = ends.last().cloned().unwrap_or(0);
+        ends.push(at + c.l);
+    }
+    assert!(ends.contains(&1000));
+    assert!(ends.contains(&3000));
+}
+
+#[test]
+fn bad_cut_table_falls_back_to_chunking() {
+    let content: Vec<u8> = (0..10000).map(|i| (i * 7 % 251) as u8).collect();
+    let (index, bs) = store_with_cuts("bad", &content, vec![1000, 20000]);
+    assert_eq!(index.i[1].kind, 2);
+    assert_content(&index, &bs, &content);
+}
 
+#[test]
+fn vanished_files_are_errors() {
+    use std::io::Write;
+    let dir = ::testing::temp_dir("serializer-vanished");
+    for name in &["a", "b"] {
+        File::create(dir.join(name)).unwrap().write_all(&vec![1; 10000]).unwrap();
+    }
+    let index = from_host(dir.as_os_str().to_owned()).unwrap();
+    ::std::fs::remove_file(dir.join("b")).unwrap();
+
+    for jobs in 1..3 {
+        match index.chunk_stats(&::blockstore::in_memory(), false, &StoreOptions {
+            jobs:     jobs,
+            ..::testing::options()
+        }) {
+            Err(Error::Io(_, _)) => (),
+            _ => panic!("expected an error reading a vanished file"),
+        }
+    }
+}
+
+#[test]
+fn files_rewritten_in_place_are_not_reused() {
+    use std::io::Write;
+    let dir = ::testing::temp_dir("serializer-rewritten");
+    let root = dir.join("root");
+    ::std::fs::create_dir(&root).unwrap();
+    File::create(root.join("a")).unwrap().write_all(b"before").unwrap();
+
+    let mut bs = ::blockstore::in_memory();
+    let mut parent = from_host(root.as_os_str().to_owned()).unwrap();
+    parent.store_inodes(&mut bs, None, true, &::testing::options()).unwrap();
+    parent.save_host_meta(&dir.join("host")).unwrap();
+    for i in &mut parent.i {
+        i.host = HostMeta::default();
+    }
+    parent.load_host_meta(&dir.join("host"));
+
+    // same inode, same size, and most likely the same second
+    ::std::fs::OpenOptions::new().write(true).open(root.join("a")).unwrap().write_all(b"after!").unwrap();
+
+    let mut index = from_host(root.as_os_str().to_owned()).unwrap();
+    index.store_inodes(&mut bs, Some(&parent), true, &::testing::options()).unwrap();
+    let mut stored = Vec::new();
+    index.i[index.paths()["/a"] as usize].reader(&bs).read_to_end(&mut stored).unwrap();
+    assert_eq!(stored, b"after!");
+}
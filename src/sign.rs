@@ -0,0 +1,63 @@
+use error::{Error, Result};
+use hex::{ToHex, FromHex};
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// ed25519 signatures over a digest of an index file.
+// the file names the blocks of the index with their offsets and lengths, and they cover the whole index
+// through the tree of directory nodes, so as long as every block is checked against its hash,
+// the signature covers every inode and block.
+
+fn read_hex(path: &Path) -> Result<Vec<u8>> {
+    let mut s = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut s))
+         .map_err(|e| Error::Io(format!("cannot read {}", path.display()), e)));
+    Vec::<u8>::from_hex(s.trim()).map_err(|e| Error::Invalid(format!("{} is not hex: {}", path.display(), e)))
+}
+
+/// write b as hex, with mode as the permissions of a new file
+fn write_hex(path: &Path, b: &[u8], mode: u32) -> Result<()> {
+    OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(path)
+        .and_then(|mut f| writeln!(f, "{}", b.to_hex()))
+        .map_err(|e| Error::Io(format!("cannot write {}", path.display()), e))
+}
+
+fn keypair(seed: &[u8]) -> Result<Ed25519KeyPair> {
+    Ed25519KeyPair::from_seed_unchecked(seed).map_err(|_| Error::Invalid("invalid secret key".to_owned()))
+}
+
+/// write a new secret key to path. returns the public key
+pub fn keygen(path: &Path) -> Result<Vec<u8>> {
+    let mut seed = [0; 32];
+    try!(File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut seed))
+         .map_err(|e| Error::Io("cannot read /dev/urandom".to_owned(), e)));
+    let kp = try!(keypair(&seed));
+    try!(write_hex(path, &seed, 0o600));
+    Ok(kp.public_key().as_ref().to_vec())
+}
+
+/// the public key for the secret key at path
+pub fn public_key(key: &Path) -> Result<Vec<u8>> {
+    let kp = try!(keypair(&try!(read_hex(key))));
+    Ok(kp.public_key().as_ref().to_vec())
+}
+
+/// sign root with the secret key at key and write the signature to sig
+pub fn sign(key: &Path, root: &[u8], sig: &Path) -> Result<()> {
+    let kp = try!(keypair(&try!(read_hex(key))));
+    write_hex(sig, kp.sign(root).as_ref(), 0o644)
+}
+
+/// check the signature at sig over root, made by the hex encoded pubkey
+pub fn verify(pubkey: &str, root: &[u8], sig: &Path) -> Result<()> {
+    let pubkey = try!(Vec::<u8>::from_hex(pubkey).map_err(|e| Error::Invalid(format!("public key is not hex: {}", e))));
+    if !sig.exists() {
+        return Err(Error::Signature("index is not signed".to_owned()));
+    }
+    let s = try!(read_hex(sig));
+    signature::UnparsedPublicKey::new(&signature::ED25519, &pubkey).verify(root, &s)
+        .map_err(|_| Error::Signature("signature does not match".to_owned()))
+}
@@ -0,0 +1,144 @@
+use byteorder::{LittleEndian, BigEndian, ReadBytesExt};
+use elfkit;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// finds format specific block boundaries in a host file,
+/// so that blocks line up with the structure of the content
+pub trait Splitter {
+    fn name(&self) -> &'static str;
+
+    /// offsets into the file where a block must end,
+    /// None if the file isn't of a format this splitter understands,
+    /// or an error if it is, but the cuts can't be trusted
+    fn cuts(&self, f: &mut File) -> Option<Result<Vec<usize>, String>>;
+}
+
+/// section type carrying a list of u32 cut offsets, written at build time
+pub const ELF_CUT_SECTION: u32 = 0x6fffff01;
+
+/// ELF files annotated with explicit cut offsets in an ELF_CUT_SECTION
+pub struct ElfCutSection;
+
+impl Splitter for ElfCutSection {
+    fn name(&self) -> &'static str {
+        "elf-cut-section"
+    }
+
+    fn cuts(&self, f: &mut File) -> Option<Result<Vec<usize>, String>> {
+        let mut elf = match elfkit::Elf::from_reader(f) {
+            Err(_) => return None,
+            Ok(elf) => elf,
+        };
+        let mut r = None;
+        for sec in elf.sections.drain(..) {
+            if sec.header.shtype == elfkit::types::SectionType(ELF_CUT_SECTION) {
+                let raw = match sec.content.into_raw() {
+                    None => return Some(Err(String::from("cut section has no content"))),
+                    Some(raw) => raw,
+                };
+                if raw.len() % 4 != 0 {
+                    return Some(Err(format!("cut section is {} bytes, not a list of u32", raw.len())));
+                }
+                let mut rr = Vec::new();
+                let mut io = &raw[..];
+                while let Ok(o) = read_u32(&elf.header, &mut io) {
+                    rr.push(o as usize);
+                }
+                r = Some(Ok(rr));
+            }
+        }
+        r
+    }
+}
+
+/// a number in the byte order of an ELF file
+fn read_u32<R: Read>(eh: &elfkit::Header, r: &mut R) -> io::Result<u32> {
+    match eh.ident_endianness {
+        elfkit::types::Endianness::LittleEndian => r.read_u32::<LittleEndian>(),
+        elfkit::types::Endianness::BigEndian    => r.read_u32::<BigEndian>(),
+    }
+}
+
+/// any ELF file, cut at the start and end of every section with content in the file
+pub struct ElfSections;
+
+impl Splitter for ElfSections {
+    fn name(&self) -> &'static str {
+        "elf-sections"
+    }
+
+    fn cuts(&self, f: &mut File) -> Option<Result<Vec<usize>, String>> {
+        let elf = match elfkit::Elf::from_reader(f) {
+            Err(_) => return None,
+            Ok(elf) => elf,
+        };
+        let mut r = Vec::new();
+        for sec in &elf.sections {
+            if sec.header.shtype == elfkit::types::SectionType::NULL ||
+                sec.header.shtype == elfkit::types::SectionType::NOBITS {
+                continue;
+            }
+            r.push(sec.header.offset as usize);
+            r.push((sec.header.offset + sec.header.size) as usize);
+        }
+        Some(Ok(r))
+    }
+}
+
+/// all splitters, by name
+pub fn by_name(name: &str) -> Option<Box<Splitter>> {
+    match name {
+        "elf-cut-section" => Some(Box::new(ElfCutSection)),
+        "elf-sections"    => Some(Box::new(ElfSections)),
+        _ => None,
+    }
+}
+
+/// splitters used when none are chosen explicitly
+pub fn default() -> Vec<Box<Splitter>> {
+    vec![Box::new(ElfCutSection)]
+}
+
+/// ask each splitter in order for cuts, the first one that understands the file wins.
+/// the cuts are validated against the file, see validate
+pub fn split(splitters: &Vec<Box<Splitter>>, f: &mut File) -> io::Result<Option<(&'static str, Result<Vec<usize>, String>)>> {
+    let len = try!(f.metadata()).len() as usize;
+    for splitter in splitters {
+        try!(f.seek(SeekFrom::Start(0)));
+        if let Some(cuts) = splitter.cuts(f) {
+            return Ok(Some((splitter.name(), cuts.and_then(|cuts| validate(cuts, len)))));
+        }
+    }
+    Ok(None)
+}
+
+/// sorts and dedups cuts and drops the ones at the start or end of the file, which cut nothing.
+/// a cut beyond the end means the table doesn't belong to this file, and it's rejected entirely
+pub fn validate(mut cuts: Vec<usize>, len: usize) -> Result<Vec<usize>, String> {
+    if let Some(cut) = cuts.iter().find(|cut| **cut > len) {
+        return Err(format!("cut at {} is beyond the end of the file at {}", cut, len));
+    }
+    cuts.retain(|cut| *cut > 0 && *cut < len);
+    cuts.sort_unstable();
+    cuts.dedup();
+    Ok(cuts)
+}
+
+
+#[test]
+fn validate_sorts_and_dedups() {
+    assert_eq!(validate(vec![30, 10, 20, 10, 30], 100), Ok(vec![10, 20, 30]));
+}
+
+#[test]
+fn validate_drops_cuts_at_the_edges() {
+    assert_eq!(validate(vec![0, 50, 100], 100), Ok(vec![50]));
+    assert_eq!(validate(Vec::new(), 100), Ok(Vec::new()));
+}
+
+#[test]
+fn validate_rejects_cuts_beyond_the_file() {
+    assert!(validate(vec![10, 101], 100).is_err());
+    assert!(validate(vec![::std::u32::MAX as usize], 0).is_err());
+}
@@ -0,0 +1,481 @@
+use blockstore::{self, BlockStore};
+use crypt::Crypt;
+use error::{Error, Result};
+use hash::{self, HashAlgo};
+use hex::{ToHex, FromHex};
+use index::{self, Index, ContentBlockEntry, ContentDirEntry};
+use libc::EWOULDBLOCK;
+use nix::fcntl::{flock, FlockArg};
+use overlay::{self, Overlay};
+use serializer::StoreOptions;
+use schema;
+use sign;
+use std::fs::{File, OpenOptions, create_dir_all, remove_dir_all, remove_file};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use trace::Access;
+use tree;
+
+/// a store directory: named indices next to the content directory holding their blocks
+///
+///  <store>/content     blocks, the only part that may live on untrusted storage when encrypted
+///  <store>/content/packs/<name>  blocks of a trace of the index name, in read order
+///  <store>/hash        hash function of new blocks, written by init
+///  <store>/chunking    per-file or stream, how new files are chunked, written by init
+///  <store>/secret      encryption secret, written by init
+///  <store>/lock        locked shared by mounts, and exclusively by relayout
+///  <store>/<name>      an index, pointing at its root block
+///  <store>/<name>.sig  signature of the index file
+///  <store>/<name>.host host metadata, to compare the next store against
+///  <store>/overlays/   changes made on writable mounts, by mountpoint, each with a .lock held by its mount
+pub struct Store {
+    pub path: PathBuf,
+    pub blockstore: BlockStore,
+    pub per_file: bool, //chunk every file on its own, as chosen by init
+}
+
+/// a lock on a store, held until dropped
+pub struct Lock {
+    _file: File,
+}
+
+fn lock_file(path: &Path, arg: FlockArg, busy: &str) -> Result<Lock> {
+    // a lock on a file opened for reading is good enough, so mounting needs no write access
+    let f = try!(File::open(path).or_else(|_| OpenOptions::new().create(true).write(true).open(path))
+                 .map_err(|e| Error::Io(format!("cannot open {}", path.display()), e)));
+    match flock(f.as_raw_fd(), arg) {
+        Ok(()) => Ok(Lock {
+            _file: f,
+        }),
+        Err(e) if e.errno() as i32 == EWOULDBLOCK => Err(Error::Invalid(busy.to_owned())),
+        Err(e) => Err(Error::Io(format!("cannot lock {}", path.display()), io::Error::from_raw_os_error(e.errno() as i32))),
+    }
+}
+
+fn read_setting(path: &Path) -> Result<Option<String>> {
+    let mut s = String::new();
+    match File::open(path) {
+        Err(_) => return Ok(None),
+        Ok(mut f) => try!(f.read_to_string(&mut s).map_err(|e| Error::Io(format!("cannot read {}", path.display()), e))),
+    };
+    Ok(Some(s.trim().to_owned()))
+}
+
+/// write a setting, with mode as the permissions of a new file
+fn write_setting(path: &Path, s: &str, mode: u32) -> Result<()> {
+    OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(path)
+        .and_then(|mut f| writeln!(f, "{}", s))
+        .map_err(|e| Error::Io(format!("cannot write {}", path.display()), e))
+}
+
+/// the hash function recorded in the store. stores created before init have none
+fn store_hash(path: &Path) -> Result<Option<HashAlgo>> {
+    match try!(read_setting(&path.join("hash"))) {
+        None => Ok(None),
+        Some(name) => HashAlgo::by_name(&name).map(Some).ok_or(Error::Invalid(format!("unknown hash function {} in store", name))),
+    }
+}
+
+/// whether the store chunks every file on its own. stores created before it was chosen stream them
+fn store_per_file(path: &Path) -> Result<bool> {
+    match try!(read_setting(&path.join("chunking"))) {
+        None => Ok(false),
+        Some(ref mode) if mode == "per-file" => Ok(true),
+        Some(ref mode) if mode == "stream" => Ok(false),
+        Some(mode) => Err(Error::Invalid(format!("unknown chunking {} in store", mode))),
+    }
+}
+
+/// the secret of an encrypted store
+fn store_secret(path: &Path) -> Result<Option<Vec<u8>>> {
+    match try!(read_setting(&path.join("secret"))) {
+        None => Ok(None),
+        Some(secret) => Vec::<u8>::from_hex(secret).map(Some).map_err(|_| Error::Invalid("store secret is not hex".to_owned())),
+    }
+}
+
+fn check_blocks<'a, I: Iterator<Item=&'a ContentBlockEntry>>(blockstore: &BlockStore, it: I) -> Result<()> {
+    for c in it {
+        if blockstore.get(&c.h).is_none() {
+            return Err(Error::MissingBlock(c.h.clone()));
+        }
+        if !blockstore.check(&c.h) {
+            return Err(Error::CorruptBlock(c.h.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// create a store, or check that an existing one uses the same hash function and chunking.
+/// encryption can only be turned on for a store without blocks
+pub fn init(path: &Path, algo: HashAlgo, encrypt: bool, per_file: bool) -> Result<Store> {
+    if let Some(existing) = try!(store_hash(path)) {
+        if existing != algo {
+            return Err(Error::Invalid(format!("store already uses {}", existing.name())));
+        }
+        if try!(store_per_file(path)) != per_file && path.join("chunking").exists() {
+            return Err(Error::Invalid(format!("store already chunks {}", if per_file { "as a stream" } else { "per file" })));
+        }
+    }
+    try!(create_dir_all(path.join("content")).map_err(|e| Error::Io(format!("cannot create {}", path.display()), e)));
+    if encrypt && try!(store_secret(path)).is_none() {
+        let has_blocks = ::std::fs::read_dir(path.join("content"))
+            .map(|mut entries| entries.next().is_some()).unwrap_or(false);
+        if has_blocks {
+            return Err(Error::Invalid("store already has unencrypted blocks".to_owned()));
+        }
+        let mut secret = [0; 32];
+        try!(File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut secret))
+             .map_err(|e| Error::Io("cannot read /dev/urandom".to_owned(), e)));
+        // only the owner of the store may read it
+        try!(write_setting(&path.join("secret"), &secret.to_hex(), 0o600));
+    }
+    try!(write_setting(&path.join("hash"), algo.name(), 0o644));
+    try!(write_setting(&path.join("chunking"), if per_file { "per-file" } else { "stream" }, 0o644));
+    open(path)
+}
+
+/// open a store, creating it with the defaults if it doesn't exist
+pub fn open_or_init(path: &Path) -> Result<Store> {
+    match open(path) {
+        Err(Error::NotInitialized(_)) => init(path, HashAlgo::Sha256, false, false),
+        r => r,
+    }
+}
+
+/// open an existing store
+pub fn open(path: &Path) -> Result<Store> {
+    let bsp = path.join("content");
+    if !bsp.is_dir() {
+        return Err(Error::NotInitialized(path.to_owned()));
+    }
+    let mut bs = try!(blockstore::new(bsp.to_string_lossy().into_owned()));
+    bs.hash  = try!(store_hash(path)).unwrap_or(HashAlgo::Sha256);
+    bs.crypt = try!(store_secret(path)).map(Crypt::new);
+    Ok(Store{
+        path: path.to_owned(),
+        blockstore: bs,
+        per_file: try!(store_per_file(path)),
+    })
+}
+
+impl Store {
+    pub fn encrypted(&self) -> bool {
+        self.blockstore.crypt.is_some()
+    }
+
+    fn index_path(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    fn signature_path(&self, name: &str) -> PathBuf {
+        self.path.join(name.to_owned() + ".sig")
+    }
+
+    fn host_meta_path(&self, name: &str) -> PathBuf {
+        self.path.join(name.to_owned() + ".host")
+    }
+
+    /// the hash of the root block of a named index
+    pub fn root_hash(&self, name: &str) -> Result<Vec<u8>> {
+        let hi = try!(Index::load_from_file(&self.index_path(name)));
+        Ok(root_hash(&hi))
+    }
+
+    /// store a host directory or file under name and return its root hash.
+    /// files unchanged since the parent index are not read again
+    pub fn store_tree(&mut self, root: &Path, name: &str, parent: Option<&str>, opts: &StoreOptions) -> Result<Vec<u8>> {
+        let parent = match parent {
+            None => None,
+            Some(parent) => {
+                let mut parent_index = try!(self.load(parent, None, false));
+                parent_index.load_host_meta(&self.host_meta_path(parent));
+                Some(parent_index)
+            },
+        };
+        let mut hi = try!(index::from_host(root.as_os_str().to_owned()));
+        try!(hi.store_inodes(&mut self.blockstore, parent.as_ref(), self.per_file, opts));
+        try!(hi.save_host_meta(&self.host_meta_path(name)));
+        self.save(name, &hi)
+    }
+
+    /// store a single file read from r under name and return its root hash
+    pub fn store_stream<R: Read>(&mut self, r: R, filename: &str, name: &str) -> Result<Vec<u8>> {
+        let mut hi = index::from_stream(filename);
+        try!(hi.store_stream(&mut self.blockstore, r));
+        self.save(name, &hi)
+    }
+
+    fn save(&mut self, name: &str, hi: &Index) -> Result<Vec<u8>> {
+        let mut root = try!(hi.store_tree(&mut self.blockstore));
+        try!(root.save_to_file(&self.index_path(name)));
+        Ok(root_hash(&root))
+    }
+
+    /// load a named index and resolve it down to the inodes.
+    /// lazy leaves the directories of an index v2 to be loaded when they are looked at.
+    /// with a public key, the index must be signed by it and its root and tree blocks must match their hashes.
+    /// content blocks are checked by the cache as they are read
+    pub fn load(&self, name: &str, pubkey: Option<&str>, lazy: bool) -> Result<Index> {
+        let bs = &self.blockstore;
+        let buf = try!(Index::read_file(&self.index_path(name)));
+        if let Some(pubkey) = pubkey {
+            try!(sign::verify(pubkey, &self.signed_digest(&buf), &self.signature_path(name)));
+        }
+        let mut hi = try!(Index::decode_file(&self.index_path(name), &buf));
+        if pubkey.is_some() {
+            try!(check_blocks(bs, hi.c.as_ref().map(|c| c.iter()).unwrap_or([].iter())));
+        }
+        if hi.v == tree::VERSION {
+            hi = try!(hi.from_tree(bs));
+            if pubkey.is_some() {
+                // every node is checked before it is parsed
+                let mut at = 0;
+                while at < hi.i.len() {
+                    if let Some(tree) = hi.i[at].tree.clone() {
+                        try!(check_blocks(bs, tree.iter()));
+                    }
+                    try!(hi.load_dir(bs, at as u64));
+                    at += 1;
+                }
+            } else if !lazy {
+                try!(hi.load_all(bs));
+            }
+        }
+        while let Some(c) = hi.c.as_ref().map(|c| c.clone()) {
+            if pubkey.is_some() {
+                try!(check_blocks(bs, c.iter()));
+            }
+            hi = try!(hi.load_index(bs));
+        }
+        Ok(hi)
+    }
+
+    /// load an index by the hash of its root block, whether or not it has a name
+    pub fn load_hash(&self, root: &[u8], lazy: bool) -> Result<Index> {
+        let mut hi = try!(Index::from_root(&self.blockstore, root));
+        if !lazy {
+            try!(hi.load_all(&self.blockstore));
+        }
+        Ok(hi)
+    }
+
+    /// rewrite a named index as the current version. returns the version it had, the new root hash,
+    /// and whether it was signed. a signature doesn't cover the new index, so it is removed
+    pub fn migrate(&mut self, name: &str) -> Result<(u16, Vec<u8>, bool)> {
+        let v = try!(Index::load_from_file(&self.index_path(name))).v;
+        if v == schema::CURRENT {
+            return Ok((v, try!(self.root_hash(name)), false));
+        }
+        let hi = try!(self.load(name, None, false));
+        let root = try!(self.save(name, &hi));
+        let signed = self.signature_path(name).exists();
+        if signed {
+            try!(remove_file(self.signature_path(name))
+                 .map_err(|e| Error::Io(format!("cannot remove {}", self.signature_path(name).display()), e)));
+        }
+        Ok((v, root, signed))
+    }
+
+    fn lock(&self, arg: FlockArg, busy: &str) -> Result<Lock> {
+        lock_file(&self.path.join("lock"), arg, busy)
+    }
+
+    /// held by a mount for as long as it serves blocks, so relayout doesn't move them meanwhile
+    pub fn lock_mounted(&self) -> Result<Lock> {
+        self.lock(FlockArg::LockSharedNonblock, "the store is being relaid out")
+    }
+
+    fn overlay_path(&self, mountpoint: &Path) -> Result<PathBuf> {
+        let mountpoint = try!(mountpoint.canonicalize().map_err(|e| Error::Io(format!("cannot find {}", mountpoint.display()), e)));
+        let id = hash::digest(HashAlgo::Sha256, mountpoint.as_os_str().as_bytes());
+        Ok(self.path.join("overlays").join(id.to_hex()))
+    }
+
+    /// the names of all indices, sorted
+    pub fn list(&self) -> Result<Vec<String>> {
+        let entries = try!(::std::fs::read_dir(&self.path).and_then(|entries| entries.collect::<::std::io::Result<Vec<_>>>())
+                           .map_err(|e| Error::Io(format!("cannot read {}", self.path.display()), e)));
+        let mut names: Vec<String> = entries.into_iter()
+            .filter(|e| e.file_type().map(|t| t.is_file()).unwrap_or(false))
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|name| name != "hash" && name != "chunking" && name != "secret" && name != "lock" && !name.ends_with(".sig") && !name.ends_with(".host"))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// the hash of the root block of a named index of the current version, which load_hash takes
+    pub fn tree_root(&self, name: &str) -> Result<Vec<u8>> {
+        let hi = try!(Index::load_from_file(&self.index_path(name)));
+        if hi.v != schema::CURRENT {
+            return Err(Error::Invalid(format!("index {:?} is version {}, migrate it first", name, hi.v)));
+        }
+        Ok(hash::normalize(&root_hash(&hi)))
+    }
+
+    /// the lock a writable mount at mountpoint holds, so its changes aren't committed under it
+    fn lock_overlay(&self, mountpoint: &Path, busy: &str) -> Result<Lock> {
+        let path = try!(self.overlay_path(mountpoint));
+        try!(create_dir_all(path.parent().unwrap()).map_err(|e| Error::Io(format!("cannot create {}", path.display()), e)));
+        lock_file(&path.with_extension("lock"), FlockArg::LockExclusiveNonblock, busy)
+    }
+
+    /// the writable layer for mounting an index, by name or root hash, at mountpoint,
+    /// with the lock to hold while it is mounted.
+    /// changes left by an earlier mount of the same index there are kept
+    pub fn overlay(&self, mountpoint: &Path, name: &str) -> Result<(Overlay, Lock)> {
+        let root = if self.index_path(name).exists() {
+            try!(self.tree_root(name))
+        } else {
+            hash::normalize(&try!(Vec::<u8>::from_hex(name).map_err(|_| Error::NotFound(format!("index {}", name)))))
+        };
+        let lock = try!(self.lock_overlay(mountpoint, &format!("{} is already mounted writable", mountpoint.display())));
+        Ok((try!(overlay::open(&try!(self.overlay_path(mountpoint)), &root.to_hex())), lock))
+    }
+
+    /// store the index mounted writable at mountpoint with its changes under name, and return its root hash.
+    /// only changed files are read, everything else keeps its content entries.
+    /// the changes are dropped once stored, and it is refused while the mountpoint is still mounted
+    pub fn commit(&mut self, mountpoint: &Path, name: &str, opts: &StoreOptions) -> Result<Vec<u8>> {
+        let _lock = try!(self.lock_overlay(mountpoint, &format!("{} is mounted, unmount it first", mountpoint.display())));
+        let overlay = try!(overlay::load(&try!(self.overlay_path(mountpoint))));
+        let root = try!(Vec::<u8>::from_hex(&overlay.base).map_err(|_| Error::Invalid(format!("{} has no base index", overlay.path.display()))));
+        let mut hi = try!(self.load_hash(&root, false));
+        try!(overlay.apply(&mut hi, &self.blockstore));
+        hi.prune();
+        try!(hi.store_inodes(&mut self.blockstore, None, self.per_file, opts));
+        let root = try!(self.save(name, &hi));
+        try!(remove_dir_all(&overlay.path).map_err(|e| Error::Io(format!("cannot remove {}", overlay.path.display()), e)));
+        Ok(root)
+    }
+
+    /// store the blocks that the reads of a trace of a mount of a named index hit, one after another
+    /// in the order they were first read, so reading the same way again is sequential.
+    /// reads of paths that aren't in the index are skipped. returns the number of blocks and of reads used.
+    /// refused while the store is mounted
+    pub fn relayout(&mut self, name: &str, accesses: &[Access]) -> Result<(usize, usize)> {
+        let _lock = try!(self.lock(FlockArg::LockExclusiveNonblock, "the store is mounted, unmount it first"));
+        let mut hi = try!(self.load(name, None, true));
+        let mut ids = Vec::new();
+        let mut used = 0;
+        for a in accesses {
+            let inode = match hi.lookup(&self.blockstore, &a.path) {
+                Err(Error::NotFound(_)) => continue,
+                r => try!(r),
+            };
+            let content = match hi.i[inode as usize].content {
+                Some(ref content) if hi.i[inode as usize].inline.is_none() => content,
+                _ => continue,
+            };
+            let mut at = 0;
+            for c in content {
+                if at < a.offset + a.len && a.offset < at + c.l && !c.is_hole() {
+                    ids.push(c.h.clone());
+                }
+                at += c.l;
+            }
+            used += 1;
+        }
+        let packed = try!(self.blockstore.pack(name, &ids));
+        Ok((packed, used))
+    }
+
+    /// sign a named index with the secret key at key. returns its root and the public key
+    pub fn sign(&self, name: &str, key: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+        let buf = try!(Index::read_file(&self.index_path(name)));
+        try!(sign::sign(key, &self.signed_digest(&buf), &self.signature_path(name)));
+        Ok((try!(self.root_hash(name)), try!(sign::public_key(key))))
+    }
+
+    /// what a signature covers: the whole index file, so its version and every entry
+    /// of the blocks it points at, offsets and lengths included
+    fn signed_digest(&self, buf: &[u8]) -> Vec<u8> {
+        hash::digest(self.blockstore.hash, buf)
+    }
+}
+
+/// the hashes of the blocks an index points at, for v2 the hash of its root block
+fn root_hash(hi: &Index) -> Vec<u8> {
+    hi.c.as_ref().map(|c| c.iter().flat_map(|c| c.h.clone()).collect()).unwrap_or(Vec::new())
+}
+
+impl Index {
+    /// the inode at a path like /a/b, loading the directories on the way
+    pub fn lookup(&mut self, blockstore: &BlockStore, path: &str) -> Result<u64> {
+        let mut inode = 0;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            try!(self.load_dir(blockstore, inode));
+            inode = match self.i[inode as usize].dir.as_ref().and_then(|dir| dir.get(name)) {
+                None => return Err(Error::NotFound(path.to_owned())),
+                Some(e) => e.i,
+            };
+        }
+        Ok(inode)
+    }
+
+    /// the entries of a directory, sorted by name
+    pub fn read_dir(&mut self, blockstore: &BlockStore, inode: u64) -> Result<Vec<(String, ContentDirEntry)>> {
+        try!(self.load_dir(blockstore, inode));
+        let dir = match self.i.get(inode as usize).and_then(|i| i.dir.as_ref()) {
+            None => return Err(Error::Invalid(format!("inode {} is not a directory", inode))),
+            Some(dir) => dir,
+        };
+        let mut r: Vec<(String, ContentDirEntry)> = dir.iter().map(|(name, e)| (name.clone(), e.clone())).collect();
+        r.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(r)
+    }
+
+    /// the content of a regular file
+    pub fn open<'a>(&self, blockstore: &'a BlockStore, inode: u64) -> Result<Box<Read + 'a>> {
+        match self.i.get(inode as usize) {
+            Some(i) if i.kind != 1 && (i.inline.is_some() || i.content.is_some()) => Ok(i.reader(blockstore)),
+            _ => Err(Error::Invalid(format!("inode {} is not a file", inode))),
+        }
+    }
+}
+
+#[test]
+fn signatures_cover_the_whole_index_file() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = ::testing::temp_dir("sign");
+    let mut store = open_or_init(&dir.join("store")).unwrap();
+    store.store_stream(&b"hello"[..], "a", "one").unwrap();
+    let pubkey = sign::keygen(&dir.join("key")).unwrap().to_hex();
+    assert_eq!(dir.join("key").metadata().unwrap().permissions().mode() & 0o777, 0o600);
+    store.sign("one", &dir.join("key")).unwrap();
+    assert!(store.load("one", Some(&pubkey), false).is_ok());
+
+    // the signed entry stays first, with another block after it
+    let mut hi = Index::load_from_file(&store.index_path("one")).unwrap();
+    let mut c = hi.c.take().unwrap();
+    let extra = c[0].clone();
+    c[0].l = 0;
+    c.push(extra);
+    hi.c = Some(c);
+    hi.save_to_file(&store.index_path("one")).unwrap();
+    match store.load("one", Some(&pubkey), false) {
+        Err(Error::Signature(_)) => (),
+        _ => panic!("expected a bad signature"),
+    }
+}
+
+#[test]
+fn the_secret_is_private() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = ::testing::temp_dir("secret");
+    init(&dir, HashAlgo::Sha256, true, false).unwrap();
+    assert_eq!(dir.join("secret").metadata().unwrap().permissions().mode() & 0o777, 0o600);
+}
+
+#[test]
+fn chunking_is_chosen_at_init() {
+    let dir = ::testing::temp_dir("chunking");
+    init(&dir, HashAlgo::Sha256, false, true).unwrap();
+    assert!(open(&dir).unwrap().per_file);
+    assert!(init(&dir, HashAlgo::Sha256, false, false).is_err());
+    assert!(open(&dir).unwrap().list().unwrap().is_empty());
+}
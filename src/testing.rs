@@ -0,0 +1,39 @@
+//! fixtures shared by the tests
+
+use serializer::StoreOptions;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// a scratch directory, removed with everything in it when dropped, also when a test fails
+pub struct TempDir(PathBuf);
+
+/// a new empty scratch directory, named after the test using it
+pub fn temp_dir(name: &str) -> TempDir {
+    let path = ::std::env::temp_dir().join(format!("archon-{}-{}", name, ::std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    TempDir(path)
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// options to store with in tests: one job, nothing inline and no splitters
+pub fn options() -> StoreOptions {
+    StoreOptions {
+        jobs:      1,
+        inline:    0,
+        splitters: Vec::new(),
+    }
+}
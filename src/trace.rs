@@ -0,0 +1,93 @@
+use error::{Error, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+// a trace is the order a mount was read in, one read per line:
+//
+//   <offset> <length> <path from the root of the mount>
+//
+// files are recorded by path, since inode numbers depend on which directories were loaded first.
+
+/// a read of a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Access {
+    pub path:   String,
+    pub offset: u64,
+    pub len:    u64,
+}
+
+/// parse one line of a trace
+fn parse(line: &str) -> Option<Access> {
+    let mut parts = line.splitn(3, ' ');
+    let offset = parts.next().and_then(|o| o.parse().ok());
+    let len    = parts.next().and_then(|l| l.parse().ok());
+    match (offset, len, parts.next()) {
+        (Some(offset), Some(len), Some(path)) => Some(Access {
+            path:   path.to_owned(),
+            offset: offset,
+            len:    len,
+        }),
+        _ => None,
+    }
+}
+
+/// the reads of a trace file, in the order they happened
+pub fn load(path: &Path) -> Result<Vec<Access>> {
+    let f = try!(File::open(path).map_err(|e| Error::Io(format!("cannot open trace {}", path.display()), e)));
+    let mut r = Vec::new();
+    for (n, line) in BufReader::new(f).lines().enumerate() {
+        let line = try!(line.map_err(|e| Error::Io(format!("cannot read trace {}", path.display()), e)));
+        if line.is_empty() {
+            continue;
+        }
+        match parse(&line) {
+            None => return Err(Error::Invalid(format!("{}:{}: not a read", path.display(), n + 1))),
+            Some(a) => r.push(a),
+        }
+    }
+    Ok(r)
+}
+
+/// writes the reads of a mount to a trace file as they happen
+pub struct Recorder {
+    out: BufWriter<File>,
+}
+
+pub fn record(path: &Path) -> Result<Recorder> {
+    let f = try!(File::create(path).map_err(|e| Error::Io(format!("cannot create trace {}", path.display()), e)));
+    Ok(Recorder {
+        out: BufWriter::new(f),
+    })
+}
+
+impl Recorder {
+    pub fn read(&mut self, path: &str, offset: u64, len: u64) {
+        if let Err(e) = writeln!(self.out, "{} {} {}", offset, len, path) {
+            println!("cannot write trace: {}", e);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.out.flush() {
+            println!("cannot write trace: {}", e);
+        }
+    }
+}
+
+#[test]
+fn recorded_reads_load_in_order() {
+    let dir = ::testing::temp_dir("trace");
+    let path = dir.join("trace");
+    {
+        let mut r = record(&path).unwrap();
+        r.read("/bin/sh", 0, 4096);
+        r.read("/a file", 10, 5);
+        r.flush();
+    }
+    let reads = load(&path).unwrap();
+    assert_eq!(reads, vec![
+        Access{path: "/bin/sh".to_owned(), offset: 0, len: 4096},
+        Access{path: "/a file".to_owned(), offset: 10, len: 5},
+    ]);
+}
@@ -0,0 +1,290 @@
+use blockstore::BlockStore;
+use hash;
+use chunker::Chunker;
+use error::{Error, Result};
+use index::*;
+use readchain::{Take, Chain};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+/// index v2: every directory is a node of its own blocks, which hold the blocks of its
+/// subdirectories. together they form a merkle tree below the root node, so a directory
+/// can be loaded only when it is looked at.
+///
+/// a node only holds what the content of its entries is, never where or when they came
+/// from on the host, so an unchanged subtree is the same blocks in every index.
+/// that takes chunking per file: chunked as one stream, the blocks of a file may also
+/// hold the end of the file before it, which differs between indices
+#[derive(Serialize, Deserialize)]
+pub struct DirNode {
+    pub entries: Vec<NodeEntry>, //sorted by name
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NodeEntry {
+    pub name:   String,
+    pub kind:   u16,
+    pub size:   u64,
+    pub access: u16,
+
+    pub content: Option<Vec<ContentBlockEntry>>, //content blocks, or the blocks of the node of a directory
+    pub inline:  Option<Vec<u8>>,
+}
+
+pub const VERSION: u16 = 2;
+
+/// deserialize something composed of blocks
+pub fn read_entries<T>(blockstore: &BlockStore, entries: &[ContentBlockEntry]) -> Result<T>
+    where T: ::serde::de::DeserializeOwned
+{
+    let buf = try!(read_bytes(blockstore, entries));
+    T::deserialize(&mut ::rmps::Deserializer::new(&buf[..])).map_err(|e| Error::CorruptIndex(e.to_string()))
+}
+
+/// the bytes composed of blocks
+pub fn read_bytes(blockstore: &BlockStore, entries: &[ContentBlockEntry]) -> Result<Vec<u8>> {
+    let mut blocks = Vec::with_capacity(entries.len());
+    for c in entries {
+        let mut re = try!(blockstore.read(&c.h));
+        try!(re.seek(SeekFrom::Current(c.o as i64)).map_err(|_| Error::CorruptBlock(c.h.clone())));
+        blocks.push(Take::limit(re, c.l as usize));
+    }
+    let mut buf = Vec::new();
+    try!(Chain::new(Box::new(blocks.into_iter())).read_to_end(&mut buf).map_err(|e| Error::Io("cannot read blocks".to_owned(), e)));
+    Ok(buf)
+}
+
+/// chunk some serialized bytes into blocks and return the entries composing them
+fn store_bytes(blockstore: &mut BlockStore, buf: &[u8], new_blocks: &mut usize) -> Result<Vec<ContentBlockEntry>> {
+    let tv = vec![(buf, 0)];
+    let ci = Chunker::new(Box::new(tv.into_iter()), ::rollsum::Bup::new(), 12, false, blockstore.hash);
+
+    let mut cbrs = Vec::new();
+    for c in ci {
+        let c = try!(c);
+        let mut content = Vec::with_capacity(c.len);
+        for ibr in c.parts {
+            content.extend_from_slice(&buf[ibr.file_start..ibr.file_end]);
+            cbrs.push(ContentBlockEntry{
+                h: c.hash.clone(),
+                o: ibr.block_start as u64,
+                l: (ibr.file_end - ibr.file_start) as u64,
+            });
+        }
+        if try!(blockstore.insert_bytes(c.hash, &content)) {
+            *new_blocks += 1;
+        }
+    }
+    Ok(cbrs)
+}
+
+impl Index {
+    /// write all directories as nodes, bottom up, and return the index pointing at the root.
+    /// the root is a single block listing the blocks of the root node, so its hash names the whole tree
+    pub fn store_tree(&self, blockstore: &mut BlockStore) -> Result<Index> {
+        let mut nodes: HashMap<u64, Vec<ContentBlockEntry>> = HashMap::new();
+        let mut new_blocks = 0;
+
+        // subdirectories always have higher inode numbers than their parent
+        for i in self.i.iter().rev() {
+            let dir = match i.dir {
+                None => continue,
+                Some(ref dir) => dir,
+            };
+            let mut names: Vec<&String> = dir.keys().collect();
+            names.sort();
+            let entries = names.into_iter().map(|name| {
+                let e = &self.i[dir[name].i as usize];
+                NodeEntry {
+                    name:   name.clone(),
+                    kind:   e.kind,
+                    size:   match e.kind {
+                        1 => 0, //what the host reports for a directory depends on its filesystem
+                        _ => e.size,
+                    },
+                    access: e.access,
+                    content: match e.kind {
+                        1 => Some(nodes.remove(&e.inode).unwrap_or(Vec::new())),
+                        _ => e.content.clone(),
+                    },
+                    inline: e.inline.clone(),
+                }
+            }).collect();
+
+            let mut buf = Vec::new();
+            DirNode{entries: entries}.serialize(&mut ::rmps::Serializer::new(&mut buf)).unwrap();
+            let cbrs = try!(store_bytes(blockstore, &buf, &mut new_blocks));
+            nodes.insert(i.inode, cbrs);
+        }
+
+        let mut buf = Vec::new();
+        nodes.remove(&0).unwrap_or(Vec::new()).serialize(&mut ::rmps::Serializer::new(&mut buf)).unwrap();
+        let root = hash::digest(blockstore.hash, &buf);
+        if try!(blockstore.insert_bytes(root.clone(), &buf)) {
+            new_blocks += 1;
+        }
+
+        println!("done serializing {} directories ({} new blocks)", self.i.iter().filter(|i| i.dir.is_some()).count(), new_blocks);
+        Ok(Index{
+            v: VERSION,
+            i: Vec::new(),
+            c: Some(vec![ContentBlockEntry{
+                h: root,
+                o: 0,
+                l: buf.len() as u64,
+            }]),
+        })
+    }
+
+    /// an index with only the root directory, whose node is loaded on demand
+    pub fn from_tree(&self, blockstore: &BlockStore) -> Result<Index> {
+        let root = try!(read_entries(blockstore, self.c.as_ref().map(|c| &c[..]).unwrap_or(&[])));
+        Ok(Index{
+            v: VERSION,
+            i: vec![Inode{
+                inode:  0,
+                parent: 0,
+                size:   0,
+                kind:   1,
+                access: 0o775,
+
+                dir: None,
+                hash: None,
+                content: None,
+                inline: None,
+
+                host: HostMeta::default(),
+
+                host_path: ::std::ffi::OsString::new(),
+                tree: Some(root),
+            }],
+            c: None,
+        })
+    }
+
+    /// an index by the hash of its root block
+    pub fn from_root(blockstore: &BlockStore, root: &[u8]) -> Result<Index> {
+        let root = hash::normalize(root);
+        let len = try!(try!(blockstore.read(&root)).seek(SeekFrom::End(0)).map_err(|_| Error::CorruptBlock(root.clone())));
+        Index{
+            v: VERSION,
+            i: Vec::new(),
+            c: Some(vec![ContentBlockEntry{
+                h: root,
+                o: 0,
+                l: len,
+            }]),
+        }.from_tree(blockstore)
+    }
+
+    /// load the node of a directory that isn't loaded yet, adding its entries as inodes
+    pub fn load_dir(&mut self, blockstore: &BlockStore, inode: u64) -> Result<()> {
+        let tree = match self.i.get(inode as usize) {
+            Some(i) if i.dir.is_none() && i.tree.is_some() => i.tree.clone().unwrap(),
+            _ => return Ok(()),
+        };
+        let node: DirNode = try!(read_entries(blockstore, &tree));
+
+        let mut dir = HashMap::new();
+        for e in node.entries {
+            let i = self.i.len() as u64;
+            let (content, tree) = match e.kind {
+                1 => (None, e.content),
+                _ => (e.content, None),
+            };
+            self.i.push(Inode{
+                inode:  i,
+                parent: inode,
+                size:   e.size,
+                kind:   e.kind,
+                access: e.access,
+
+                dir: None,
+                hash: None,
+                content: content,
+                inline: e.inline,
+
+                host: HostMeta::default(),
+
+                host_path: ::std::ffi::OsString::new(),
+                tree: tree,
+            });
+            dir.insert(e.name, ContentDirEntry{
+                i: i,
+                k: e.kind,
+            });
+        }
+        self.i[inode as usize].dir = Some(dir);
+        Ok(())
+    }
+
+    /// load every directory
+    pub fn load_all(&mut self, blockstore: &BlockStore) -> Result<()> {
+        let mut at = 0;
+        while at < self.i.len() {
+            try!(self.load_dir(blockstore, at as u64));
+            at += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use std::io::Write;
+
+#[test]
+fn tree_loads_the_same_inodes() {
+    let dir = ::testing::temp_dir("tree");
+    ::std::fs::create_dir_all(dir.join("a/b")).unwrap();
+    ::std::fs::create_dir_all(dir.join("c")).unwrap();
+    ::std::fs::File::create(dir.join("a/b/x")).unwrap().write_all(b"hello").unwrap();
+    ::std::fs::File::create(dir.join("c/y")).unwrap().write_all(&vec![7; 10000]).unwrap();
+
+    let mut bs = ::blockstore::in_memory();
+    let mut index = from_host(dir.as_os_str().to_owned()).unwrap();
+    index.store_inodes(&mut bs, None, false, &::testing::options()).unwrap();
+
+    let mut loaded = index.store_tree(&mut bs).unwrap().from_tree(&bs).unwrap();
+    loaded.load_dir(&bs, 0).unwrap();
+    assert_eq!(loaded.i.len(), 3);
+
+    loaded.load_all(&bs).unwrap();
+    let (a, b) = (index.paths(), loaded.paths());
+    assert_eq!(a.len(), b.len());
+    for (path, i) in a {
+        let (x, y) = (&index.i[i as usize], &loaded.i[b[&path] as usize]);
+        assert_eq!(x.kind, y.kind);
+        if x.kind == 2 {
+            assert_eq!(x.size, y.size);
+            let (mut cx, mut cy) = (Vec::new(), Vec::new());
+            ::std::io::Read::read_to_end(&mut x.reader(&bs), &mut cx).unwrap();
+            ::std::io::Read::read_to_end(&mut y.reader(&bs), &mut cy).unwrap();
+            assert_eq!(cx, cy);
+        }
+    }
+}
+
+#[test]
+fn unchanged_subtrees_share_nodes() {
+    let dir = ::testing::temp_dir("subtree");
+    for &(image, extra) in &[("a", "one"), ("b", "two")] {
+        ::std::fs::create_dir_all(dir.join(image).join("sub/deeper")).unwrap();
+        ::std::fs::File::create(dir.join(image).join("sub/deeper/x")).unwrap().write_all(b"same").unwrap();
+        ::std::fs::File::create(dir.join(image).join(extra)).unwrap().write_all(extra.as_bytes()).unwrap();
+    }
+
+    let mut bs = ::blockstore::in_memory();
+    for &per_file in &[false, true] {
+        let subtrees: Vec<Vec<u8>> = ["a", "b"].iter().map(|image| {
+            let mut index = from_host(dir.join(image).into_os_string()).unwrap();
+            index.store_inodes(&mut bs, None, per_file, &::testing::options()).unwrap();
+            let mut loaded = index.store_tree(&mut bs).unwrap().from_tree(&bs).unwrap();
+            loaded.load_dir(&bs, 0).unwrap();
+            let sub = loaded.paths()["/sub"];
+            loaded.i[sub as usize].tree.as_ref().unwrap().iter().flat_map(|c| c.h.clone()).collect()
+        }).collect();
+        // chunked as one stream, x shares its block with the file next to it
+        assert_eq!(subtrees[0] == subtrees[1], per_file);
+    }
+}